@@ -1,28 +1,82 @@
 use crate::{
     ir::{Conditional, Node, Op, *},
-    Plan,
+    Comment, CompileError, Diagnostic, Input, InputType, Param, Plan,
 };
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use pest::{
-    iterators::{Pair, Pairs},
-    Parser,
+    error::{Error, ErrorVariant, InputLocation},
+    iterators::Pair,
+    pratt_parser::PrattParser,
+    Parser, Span,
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    fs,
+    ops::Range,
+    path::Path,
+    sync::OnceLock,
 };
-use std::{collections::HashSet, fmt::Debug};
 
-// experiment assignments / output parameters
-type Params = HashSet<String>;
+// What compiling has found so far
+#[derive(Default)]
+struct State {
+    // experiment assignments / output parameters
+    params: Vec<Param>,
+    // How many `if` or `switch` branches deep compiling is
+    branches: usize,
+    // A reachable `return` came before, so it might have stopped the plan
+    returned: bool,
+    // After a `return` in the same block, so never evaluated
+    unreachable: bool,
+    // Only assigned where it's unreachable, so not a param
+    dead: HashSet<String>,
+    inputs: Vec<Input>,
+    // Assigned with `let`, left out of the plan's params
+    locals: HashSet<String>,
+    // Set by an `output` declaration, the only params then
+    outputs: Option<Vec<(String, Range<usize>)>>,
+    // Variables read before anything assigned them and where, checked
+    // against the inputs
+    reads: Vec<(String, Range<usize>)>,
+    // Compiling carries on after a broken statement, see compile_stmts
+    errors: Vec<CompileError>,
+    warnings: Vec<Diagnostic>,
+}
 
 #[derive(Parser)]
 #[grammar = "../planout.pest"]
 struct PlanoutParser;
 
+impl State {
+    fn is_assigned(&self, var: &str) -> bool {
+        self.params.iter().any(|param| param.name == var) || self.dead.contains(var)
+    }
+
+    // Compiles a branch's body, whose assignments are conditional
+    fn branch<T>(&mut self, compile: impl FnOnce(&mut State) -> T) -> T {
+        self.branches += 1;
+        let compiled = compile(self);
+        self.branches -= 1;
+        compiled
+    }
+}
+
 // Compile Op::Set
-// Compiler could collect a list of assignments here
-fn compile_set(pair: Pair<Rule>, params: &mut Params) -> anyhow::Result<Node> {
+fn compile_set(pair: Pair<Rule>, state: &mut State) -> anyhow::Result<Node> {
+    let span = pair.as_span();
     let mut inner = pair.into_inner();
-    let id = inner.next().unwrap();
+    let local = inner
+        .peek()
+        .is_some_and(|pair| pair.as_rule() == Rule::op_let);
+    if local {
+        inner.next();
+    }
+    let id = next_pair(&mut inner)?;
     anyhow::ensure!(id.as_rule() == Rule::ident, "expected ident");
     let var = id.as_span().as_str().to_string();
+    let (line, column) = span.start_pos().line_col();
 
     skip_front(&mut inner, Rule::op_assign)?;
     skip_back(&mut inner, Rule::semi)?;
@@ -31,10 +85,26 @@ fn compile_set(pair: Pair<Rule>, params: &mut Params) -> anyhow::Result<Node> {
         inner
             .next()
             .ok_or(anyhow!("expected assignment to expr or value"))?,
-        params,
+        state,
     )?;
 
-    params.insert(var.clone());
+    let conditional = state.branches > 0 || state.returned;
+    match state.params.iter_mut().find(|param| param.name == var) {
+        _ if state.unreachable => {
+            state.dead.insert(var.clone());
+        }
+        Some(param) => param.conditional &= conditional,
+        None => state.params.push(Param {
+            name: var.clone(),
+            line,
+            column,
+            span: span.start()..span.end(),
+            conditional,
+        }),
+    }
+    if local {
+        state.locals.insert(var.clone());
+    }
 
     Ok(Node::Op(Op::Set {
         var,
@@ -42,20 +112,89 @@ fn compile_set(pair: Pair<Rule>, params: &mut Params) -> anyhow::Result<Node> {
     }))
 }
 
+// A header declaration, stored on the plan rather than compiled to an op
+fn compile_input(pair: Pair<Rule>, state: &mut State) -> Result<()> {
+    let mut inner = pair.into_inner();
+    skip_front(&mut inner, Rule::op_input)?;
+    let id = next_pair(&mut inner)?;
+    let name = id.as_str().to_string();
+    let ty = next_pair(&mut inner)?;
+
+    let ty = InputType::from_name(ty.as_str()).ok_or_else(|| {
+        at(
+            ty.as_span(),
+            anyhow!(
+                "unknown input type {}, expected string, number, boolean, array or map",
+                ty.as_str()
+            ),
+        )
+    })?;
+    if state.inputs.iter().any(|input| input.name == name) {
+        return Err(at(
+            id.as_span(),
+            anyhow!("input {} is already declared", name),
+        ));
+    }
+
+    state.inputs.push(Input {
+        name,
+        ty,
+        optional: inner.any(|pair| pair.as_rule() == Rule::optional),
+    });
+
+    Ok(())
+}
+
+// Lists the plan's outputs, which can take more than one declaration
+fn compile_output(pair: Pair<Rule>, state: &mut State) -> Result<()> {
+    let outputs = state.outputs.get_or_insert_with(Vec::new);
+
+    for id in pair
+        .into_inner()
+        .filter(|pair| pair.as_rule() == Rule::ident)
+    {
+        let name = id.as_str().to_string();
+        if outputs.iter().any(|(output, _)| *output == name) {
+            return Err(at(
+                id.as_span(),
+                anyhow!("output {} is already listed", name),
+            ));
+        }
+        outputs.push((name, id.as_span().start()..id.as_span().end()));
+    }
+
+    Ok(())
+}
+
+// Compile Op::Return
+fn compile_return(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
+    let mut inner = pair.into_inner();
+    skip_front(&mut inner, Rule::op_return)?;
+
+    let value = next_pair(&mut inner).and_then(|op| compile_op(op, state))?;
+    if !state.unreachable {
+        state.returned = true;
+    }
+
+    Ok(Node::Op(Op::Return {
+        value: Box::new(value),
+    }))
+}
+
 // Compile Op::Conditional
-fn compile_conditional(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
+fn compile_conditional(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
     fn compile_arm<'a>(
         inner: &mut (impl DoubleEndedIterator<Item = Pair<'a, Rule>> + Debug),
-        params: &mut Params,
+        state: &mut State,
         is_else: bool,
     ) -> Result<Conditional> {
         let when = if is_else {
             crate::ir::bool_true()
         } else {
-            next_pair(inner).and_then(|op| compile_op(op, params))?
+            next_pair(inner).and_then(|op| compile_op(op, state))?
         };
 
-        let then = compile_block(inner, params)?;
+        let then = state.branch(|state| compile_block(inner, state))?;
 
         Ok(Conditional { when, then })
     }
@@ -67,10 +206,10 @@ fn compile_conditional(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
     while let Some(pair) = inner.next() {
         match pair.as_rule() {
             Rule::op_if | Rule::op_else_if => {
-                conds.push(compile_arm(&mut inner, params, false)?);
+                conds.push(compile_arm(&mut inner, state, false)?);
             }
             Rule::op_else => {
-                conds.push(compile_arm(&mut inner, params, true)?);
+                conds.push(compile_arm(&mut inner, state, true)?);
             }
             r => anyhow::bail!(
                 "found unexpected rule {:?} compiling conditional. maybe simplify the parser?",
@@ -82,33 +221,145 @@ fn compile_conditional(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
     Ok(Node::Op(Op::Cond { cond: conds }))
 }
 
-fn compile_dyadic_expr(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
+// Compile a switch to Op::Cond, so the first arm that holds wins
+fn compile_switch(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
     let mut inner = pair.into_inner();
-    let lhs = compile_op(next_pair(&mut inner)?, params)?;
+    skip_front(&mut inner, Rule::op_switch)?;
+    skip_front(&mut inner, Rule::block_start)?;
+    skip_back(&mut inner, Rule::block_end)?;
 
-    let verb = next_pair(&mut inner)?;
+    let mut conds = Vec::new();
+
+    for arm in inner {
+        let mut arm = arm.into_inner();
+        let when = next_pair(&mut arm).and_then(|op| compile_op(op, state))?;
+        skip_front(&mut arm, Rule::op_arrow)?;
 
-    let rhs = compile_op(next_pair(&mut inner)?, params)?;
+        let then = state.branch(|state| match arm.peek().map(|pair| pair.as_rule()) {
+            Some(Rule::block_start) => compile_block(&mut arm, state),
+            _ => Ok(sequence(compile_stmts(&mut arm, state))),
+        })?;
 
-    let op = match verb.as_str() {
-        "*" => Op::Product {
-            values: vec![lhs, rhs],
+        conds.push(Conditional { when, then });
+    }
+
+    Ok(Node::Op(Op::Cond { cond: conds }))
+}
+
+// Binding power, lowest first. Every binary operator is left associative.
+fn pratt() -> &'static PrattParser<Rule> {
+    static PRATT: OnceLock<PrattParser<Rule>> = OnceLock::new();
+
+    PRATT.get_or_init(|| {
+        use pest::pratt_parser::{Assoc::Left, Op};
+
+        PrattParser::new()
+            .op(Op::infix(Rule::op_bool_or, Left))
+            .op(Op::infix(Rule::op_bool_and, Left))
+            .op(Op::infix(Rule::op_eq, Left)
+                | Op::infix(Rule::op_ne, Left)
+                | Op::infix(Rule::op_ge, Left)
+                | Op::infix(Rule::op_le, Left)
+                | Op::infix(Rule::op_gt, Left)
+                | Op::infix(Rule::op_lt, Left))
+            .op(Op::infix(Rule::op_add, Left) | Op::infix(Rule::op_sub, Left))
+            .op(Op::infix(Rule::op_mul, Left)
+                | Op::infix(Rule::op_div, Left)
+                | Op::infix(Rule::op_mod, Left))
+            .op(Op::prefix(Rule::op_neg) | Op::prefix(Rule::op_bool_not))
+            .op(Op::postfix(Rule::index) | Op::postfix(Rule::field_access))
+    })
+}
+
+fn compile_infix(lhs: Node, op: Pair<Rule>, rhs: Node) -> Result<Node> {
+    let (left, right) = (Box::new(lhs), Box::new(rhs));
+
+    let op = match op.as_rule() {
+        Rule::op_mul => Op::Product {
+            values: vec![*left, *right],
+        },
+        Rule::op_add => Op::Sum {
+            values: vec![*left, *right],
+        },
+        // Like the reference compiler, `a - b` is `a + (-b)`
+        Rule::op_sub => Op::Sum {
+            values: vec![*left, Node::Op(Op::Negative { value: right })],
         },
-        "+" => Op::Sum {
-            values: vec![lhs, rhs],
+        Rule::op_div => Op::Divide { left, right },
+        Rule::op_mod => Op::Mod { left, right },
+        Rule::op_bool_and => Op::And {
+            values: vec![*left, *right],
         },
-        x => anyhow::bail!("unimplemented verb {}", x),
+        Rule::op_bool_or => Op::Or {
+            values: vec![*left, *right],
+        },
+        Rule::op_eq => Op::Equals { left, right },
+        // The reference compiler has no `notEquals`, it negates `equals`
+        Rule::op_ne => Op::Not {
+            value: Box::new(Node::Op(Op::Equals { left, right })),
+        },
+        Rule::op_gt => Op::GreaterThan { left, right },
+        Rule::op_lt => Op::LessThan { left, right },
+        Rule::op_ge => Op::GreaterThanOrEqualTo { left, right },
+        Rule::op_le => Op::LessThanOrEqualTo { left, right },
+        r => bail!("unimplemented infix operator {:?}", r),
+    };
+
+    Ok(Node::Op(op))
+}
+
+fn compile_prefix(op: Pair<Rule>, value: Node) -> Result<Node> {
+    let value = Box::new(value);
+
+    let op = match op.as_rule() {
+        Rule::op_neg => Op::Negative { value },
+        Rule::op_bool_not => Op::Not { value },
+        r => bail!("unimplemented prefix operator {:?}", r),
     };
 
     Ok(Node::Op(op))
 }
 
-fn compile_expr<'a>(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
+// `base[index]` and `base.field`, both the reference `index` op
+fn compile_postfix(base: Node, op: Pair<Rule>, state: &mut State) -> Result<Node> {
+    let rule = op.as_rule();
+    let mut inner = op.into_inner();
+
+    let index = match rule {
+        Rule::index => compile_op(next_pair(&mut inner)?, state)?,
+        Rule::field_access => {
+            skip_front(&mut inner, Rule::op_access)?;
+            Node::Json(next_pair(&mut inner)?.as_str().into())
+        }
+        r => bail!("unimplemented postfix operator {:?}", r),
+    };
+
+    Ok(Node::Op(Op::Index {
+        base: Box::new(base),
+        index: Box::new(index),
+    }))
+}
+
+fn compile_operation(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
+    // Both primaries and index expressions can assign
+    let state = RefCell::new(state);
+
+    let node = pratt()
+        .map_primary(|primary| compile_op(primary, &mut state.borrow_mut()))
+        .map_prefix(|op, value| compile_prefix(op, value?))
+        .map_postfix(|base, op| compile_postfix(base?, op, &mut state.borrow_mut()))
+        .map_infix(|lhs, op, rhs| compile_infix(lhs?, op, rhs?))
+        .parse(pair.into_inner());
+
+    node
+}
+
+fn compile_expr(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
     let inner = pair.into_inner();
 
     let mut vals = inner
         .into_iter()
-        .map(|op| compile_op(op, params))
+        .map(|op| compile_op(op, state))
         .collect::<Result<Vec<_>>>()?;
 
     vals.pop().ok_or(anyhow!("expected inner expression"))
@@ -116,27 +367,78 @@ fn compile_expr<'a>(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
 
 fn compile_block<'a>(
     inner: &mut (impl DoubleEndedIterator<Item = Pair<'a, Rule>> + Debug),
-    params: &mut Params,
+    state: &mut State,
 ) -> Result<Op> {
     skip_front(inner, Rule::block_start)?;
 
-    let mut ops = inner
-        // TODO, nested blocks are supported, right?
-        .take_while(|i| i.as_rule() != Rule::block_end)
-        .map(|i| compile_op(i, params))
-        .map(|res| {
-            res.and_then(|node| match node {
-                Node::Json(..) => Err(anyhow::anyhow!("unexpected json in block")),
-                Node::Op(op) => Ok(op),
-            })
-        })
-        .collect::<Result<Vec<_>>>()?;
+    let ops = compile_stmts(inner.take_while(|i| i.as_rule() != Rule::block_end), state);
 
+    Ok(sequence(ops))
+}
+
+// A single op stands alone, anything else is a seq
+fn sequence(mut ops: Vec<Op>) -> Op {
     if ops.len() == 1 {
-        Ok(ops.pop().unwrap())
+        ops.pop().unwrap()
     } else {
-        Ok(Op::Seq { seq: ops })
+        Op::Seq { seq: ops }
+    }
+}
+
+// Compiles a block's or the program's statements. One that fails is
+// recorded in state.errors and left out, so a single pass finds every
+// mistake.
+fn compile_stmts<'a>(pairs: impl Iterator<Item = Pair<'a, Rule>>, state: &mut State) -> Vec<Op> {
+    let mut ops = Vec::new();
+    let unreachable = state.unreachable;
+
+    for pair in pairs {
+        let span = pair.as_span();
+
+        if let Rule::junk | Rule::stray = pair.as_rule() {
+            state.errors.push(junk_error(span));
+            continue;
+        }
+        // Only the first, the rest of the block is just as unreachable
+        if !state.unreachable && matches!(ops.last(), Some(Op::Return { .. })) {
+            state
+                .warnings
+                .push(Diagnostic::new(span, "unreachable statement after return").warning());
+            state.unreachable = true;
+        }
+
+        match compile_op(pair, state) {
+            Ok(Node::Op(op)) => ops.push(op),
+            Ok(Node::Json(..) | Node::Array(..)) => state.errors.push(CompileError::Invalid {
+                span: Diagnostic::new(span, "constants do nothing as a statement"),
+            }),
+            Err(err) => state.errors.push(compile_error(span, err)),
+        }
     }
+
+    // The rest of the enclosing block is still reachable
+    state.unreachable = unreachable;
+    ops
+}
+
+// The recovering grammar skipped junk without saying why, so parse
+// it alone to find what was expected there
+fn junk_error(span: Span) -> CompileError {
+    let src = span.get_input();
+
+    let diagnostic = match PlanoutParser::parse(Rule::lone_stmt, &src[span.start()..]) {
+        Err(err) => {
+            // Relative to where the reparse started
+            let at = match err.location {
+                InputLocation::Pos(pos) | InputLocation::Span((pos, _)) => span.start() + pos,
+            };
+            let at = Span::new(src, at, at).expect("inside the source");
+            Diagnostic::new(at, expected(&err))
+        }
+        Ok(..) => Diagnostic::new(span, format!("unexpected `{}`", span.as_str().trim())),
+    };
+
+    CompileError::ParseError { span: diagnostic }
 }
 
 fn next_pair<'a>(
@@ -163,75 +465,1385 @@ fn skip_back<'a>(
     Ok(())
 }
 
-fn compile_array(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
+// Named arguments to a call. The op being compiled takes what it
+// needs, anything left over is an error.
+struct Args {
+    func: String,
+    named: Vec<(String, Node)>,
+    positional: Vec<Node>,
+}
+
+impl Args {
+    fn optional(&mut self, name: &str) -> Option<Box<Node>> {
+        let i = self.named.iter().position(|(n, _)| n == name)?;
+        Some(Box::new(self.named.remove(i).1))
+    }
+
+    fn required(&mut self, name: &str) -> Result<Box<Node>> {
+        self.optional(name)
+            .ok_or_else(|| anyhow!("{} is missing required argument `{}`", self.func, name))
+    }
+
+    // unit, salt and full_salt
+    fn random(&mut self) -> Result<RandomArgs> {
+        Ok(RandomArgs {
+            unit: self.required("unit")?,
+            salt: self.optional("salt"),
+            full_salt: self.optional("full_salt"),
+        })
+    }
+
+    fn positional(&mut self) -> Vec<Node> {
+        std::mem::take(&mut self.positional)
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.named.first() {
+            Some((name, _)) => bail!("{} got an unexpected argument `{}`", self.func, name),
+            None => Ok(()),
+        }
+    }
+}
+
+// Argument mistakes are reported against the whole call
+fn compile_call(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
+    let span = pair.as_span();
+    let func = pair
+        .clone()
+        .into_inner()
+        .next()
+        .map(|f| f.as_str().to_string());
+
+    lower_call(pair, state).map_err(|err| {
+        if err.is::<CompileError>() {
+            return err;
+        }
+        CompileError::InvalidArguments {
+            name: func.unwrap_or_default(),
+            span: Diagnostic::new(span, format!("{:#}", err)),
+        }
+        .into()
+    })
+}
+
+fn lower_call(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
+    let span = pair.as_span();
+    let mut inner = pair.into_inner();
+    let func = next_pair(&mut inner)?.as_str().to_string();
+
+    let mut args = Args {
+        func: func.clone(),
+        named: Vec::new(),
+        positional: Vec::new(),
+    };
+
+    for arg in inner {
+        let mut arg = arg.into_inner();
+        let first = next_pair(&mut arg)?;
+        if first.as_rule() != Rule::ident {
+            ensure!(
+                args.named.is_empty(),
+                "{} got a positional argument after a named one",
+                func
+            );
+            args.positional.push(compile_op(first, state)?);
+            continue;
+        }
+
+        let name = first.as_str().to_string();
+        skip_front(&mut arg, Rule::op_assign)?;
+        let value = compile_op(next_pair(&mut arg)?, state)?;
+
+        ensure!(
+            args.named.iter().all(|(n, _)| *n != name),
+            "{} got argument `{}` more than once",
+            func,
+            name
+        );
+        args.named.push((name, value));
+    }
+
+    // Only the null helpers take positional arguments
+    ensure!(
+        args.positional.is_empty() || matches!(func.as_str(), "coalesce" | "isNull"),
+        "{} only takes named arguments",
+        func
+    );
+
+    let op = match func.as_str() {
+        "uniformChoice" => Op::UniformChoice {
+            choices: args.required("choices")?,
+            random: args.random()?,
+        },
+        "bernoulliTrial" => Op::BernoulliTrial {
+            p: args.required("p")?,
+            random: args.random()?,
+        },
+        "weightedChoice" => Op::WeightedChoice {
+            choices: args.required("choices")?,
+            weights: args.required("weights")?,
+            random: args.random()?,
+        },
+        "randomInteger" => Op::RandomInteger {
+            min: args.required("min")?,
+            max: args.required("max")?,
+            random: args.random()?,
+        },
+        "randomFloat" => Op::RandomFloat {
+            min: args.required("min")?,
+            max: args.required("max")?,
+            random: args.random()?,
+        },
+        "sample" => Op::Sample {
+            choices: args.required("choices")?,
+            draws: args.optional("draws"),
+            random: args.random()?,
+        },
+        "bernoulliFilter" => Op::BernoulliFilter {
+            p: args.required("p")?,
+            choices: args.required("choices")?,
+            random: args.random()?,
+        },
+        // coalesce(x, y, default)
+        "coalesce" => Op::Coalesce {
+            values: args.positional(),
+        },
+        // Sugar for x == null, the reference IR has no op for it
+        "isNull" => {
+            let mut values = args.positional();
+            ensure!(
+                values.len() == 1,
+                "isNull takes exactly one argument, found {}",
+                values.len()
+            );
+            Op::Equals {
+                left: Box::new(values.remove(0)),
+                right: Box::new(Node::Json(Value::Null)),
+            }
+        }
+        f => bail!(CompileError::UnknownOperator {
+            name: f.to_string(),
+            span: Diagnostic::new(span, format!("unknown function {}", f)),
+        }),
+    };
+
+    args.finish()?;
+
+    Ok(Node::Op(op))
+}
+
+fn compile_array(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
     let mut inner = pair.into_inner();
     skip_front(&mut inner, Rule::array_start)?;
     skip_back(&mut inner, Rule::array_end)?;
 
     let values = inner
-        .map(|op| compile_expr(op, params))
+        .map(|op| compile_expr(op, state))
         .collect::<Result<Vec<_>>>()?;
 
     Ok(Node::Op(Op::Array { values }))
 }
 
+fn compile_map(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
+    let mut values = BTreeMap::new();
+
+    for entry in pair.into_inner() {
+        let mut inner = entry.into_inner();
+
+        let key = next_pair(&mut inner)?;
+        let key = match key.as_rule() {
+            Rule::string => match compile_string(key)? {
+                Node::Json(Value::String(key)) => key,
+                _ => bail!("expected string key"),
+            },
+            _ => key.as_str().to_string(),
+        };
+        // The reference IR keeps the keys next to the op's name
+        ensure!(key != "op", "`op` can't be used as a map key");
+
+        let value = compile_op(next_pair(&mut inner)?, state)?;
+        ensure!(
+            values.insert(key.clone(), value).is_none(),
+            "map key `{}` is repeated",
+            key
+        );
+    }
+
+    Ok(Node::Op(Op::Map { values }))
+}
+
+// Integers stay exact, so ones that don't fit an i64 or u64 are
+// errors rather than rounded floats
 fn compile_number(pair: Pair<Rule>) -> Result<Node> {
-    let n = next_pair(&mut pair.into_inner()).context("expected int or decimal")?;
+    let span = pair.as_span();
+    let literal = pair.as_str();
+    let out_of_range = |kind: &str| CompileError::ParseError {
+        span: Diagnostic::new(
+            span,
+            format!("{} literal {} is out of range", kind, literal),
+        ),
+    };
+    let kind = next_pair(&mut pair.into_inner()).context("expected int or decimal")?;
+    let digits = literal.replace('_', "");
+
+    let n = match kind.as_rule() {
+        Rule::integer => match digits.parse::<i64>() {
+            Ok(n) => n.into(),
+            Err(..) => digits
+                .parse::<u64>()
+                .map(serde_json::Number::from)
+                .map_err(|_| out_of_range("integer"))?,
+        },
+        _ => {
+            // Too small underflows to 0, which is as lossy as too big
+            let mantissa = digits.split(['e', 'E']).next().unwrap_or_default();
+            let zero = !mantissa.chars().any(|c| matches!(c, '1'..='9'));
+
+            digits
+                .parse::<f64>()
+                .ok()
+                .filter(|n| *n != 0.0 || zero)
+                .and_then(serde_json::Number::from_f64)
+                .ok_or_else(|| out_of_range("decimal"))?
+        }
+    };
 
-    let n: serde_json::Number = serde_json::from_str(n.as_str())?;
     Ok(Node::Json(serde_json::Value::Number(n)))
 }
 
+// Decodes JSON's escapes, \u surrogate pairs included
 fn compile_string(pair: Pair<Rule>) -> Result<Node> {
     let s = next_pair(&mut pair.into_inner()).context("expected string_inner")?;
+    let span = s.as_span();
+    let raw = span.as_str();
+
+    // The escape's `len` chars starting at byte i
+    let invalid = |i: usize, len: usize| {
+        let end = raw[i..]
+            .char_indices()
+            .nth(len)
+            .map_or(raw.len(), |(j, _)| i + j);
+        let escape = Span::new(span.get_input(), span.start() + i, span.start() + end)
+            .expect("inside the string");
+        anyhow::Error::new(CompileError::ParseError {
+            span: Diagnostic::new(escape, format!("invalid escape `{}`", escape.as_str())),
+        })
+    };
+    // The 4 hex digits after a \u at i
+    let hex = |i: usize| {
+        raw.get(i + 2..i + 6)
+            .filter(|h| h.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|h| u32::from_str_radix(h, 16).ok())
+            .ok_or_else(|| invalid(i, 6))
+    };
 
-    Ok(Node::Json(s.as_span().as_str().into()))
+    let mut decoded = String::with_capacity(raw.len());
+    let mut chars = raw.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+
+        let c = match chars.next().map(|(_, c)| c) {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let high = hex(i)?;
+                chars.nth(3);
+
+                let code = match high {
+                    0xD800..=0xDBFF => {
+                        // Must be followed by the low half of the pair
+                        let low = Some(i + 6)
+                            .filter(|&j| raw[j..].starts_with("\\u"))
+                            .and_then(|j| hex(j).ok())
+                            .filter(|low| (0xDC00..=0xDFFF).contains(low))
+                            .ok_or_else(|| invalid(i, 6))?;
+                        chars.nth(5);
+
+                        0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                    }
+                    _ => high,
+                };
+
+                char::from_u32(code).ok_or_else(|| invalid(i, 6))?
+            }
+            _ => return Err(invalid(i, 2)),
+        };
+        decoded.push(c);
+    }
+
+    Ok(Node::Json(decoded.into()))
 }
 
-fn compile_op(pair: Pair<Rule>, params: &mut Params) -> Result<Node> {
+// Errors get the span of the innermost pair they came from
+fn at(span: Span, err: anyhow::Error) -> anyhow::Error {
+    compile_error(span, err).into()
+}
+
+fn compile_error(span: Span, err: anyhow::Error) -> CompileError {
+    err.downcast::<CompileError>()
+        .unwrap_or_else(|err| CompileError::Invalid {
+            span: Diagnostic::new(span, format!("{:#}", err)),
+        })
+}
+
+fn compile_op(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
+    let span = pair.as_span();
+    compile_rule(pair, state).map_err(|err| at(span, err))
+}
+
+fn compile_rule(pair: Pair<Rule>, state: &mut State) -> Result<Node> {
     let rule_ty = pair.as_rule();
     //eprintln!("compiling ty: {:?}", rule_ty);
     //eprintln!("{:?}", pair);
     match rule_ty {
         Rule::number => compile_number(pair),
         Rule::string => compile_string(pair),
-        Rule::expr | Rule::terms => compile_expr(pair, params),
-        Rule::dyadic_expr => compile_dyadic_expr(pair, params),
-        //Rule::statement => compile_block(pair.into_inner(), params),
-        Rule::ident => Ok(Node::Op(Op::Get(Get {
-            var: pair.as_str().to_string(),
-        }))),
-        Rule::assignment => compile_set(pair, params),
-        Rule::conditional => compile_conditional(pair, params),
-        Rule::array => compile_array(pair, params),
+        Rule::boolean => Ok(Node::Json(Value::Bool(pair.as_str() == "true"))),
+        Rule::null => Ok(Node::Json(Value::Null)),
+        Rule::expr => compile_expr(pair, state),
+        Rule::bare => {
+            let mut inner = pair.into_inner();
+            skip_back(&mut inner, Rule::semi)?;
+            compile_op(next_pair(&mut inner)?, state)
+        }
+        Rule::operation => compile_operation(pair, state),
+        //Rule::statement => compile_block(pair.into_inner(), state),
+        Rule::ident => {
+            let var = pair.as_str().to_string();
+            if !state.is_assigned(&var) {
+                state
+                    .reads
+                    .push((var.clone(), pair.as_span().start()..pair.as_span().end()));
+            }
+
+            Ok(Node::Op(Op::Get(Get { var })))
+        }
+        Rule::input => bail!("inputs are declared at the top of the plan, before any statement"),
+        Rule::output => bail!("outputs are declared at the top of the plan, before any statement"),
+        Rule::assignment => compile_set(pair, state),
+        Rule::conditional => compile_conditional(pair, state),
+        Rule::nested => compile_block(&mut pair.into_inner(), state).map(Node::Op),
+        Rule::switch => compile_switch(pair, state),
+        Rule::ret => compile_return(pair, state),
+        Rule::array => compile_array(pair, state),
+        Rule::call => compile_call(pair, state),
+        Rule::map => compile_map(pair, state),
         rule => anyhow::bail!("rule {:?} isn't implemented", rule),
     }
 }
 
-pub fn compile(src: &str) -> anyhow::Result<Plan> {
-    let pairs = PlanoutParser::parse(Rule::program, src).context("parsing")?;
+// The comment rule is silent in programs so it can appear anywhere,
+// which means collecting them takes a pass of its own
+fn compile_comments(src: &str) -> std::result::Result<Vec<Comment>, CompileError> {
+    let pairs =
+        PlanoutParser::parse(Rule::comments, src).map_err(|err| CompileError::ParseError {
+            span: Diagnostic::from_pest(err),
+        })?;
 
-    let mut params = HashSet::new();
-    let mut ops = Vec::new();
+    Ok(pairs
+        .flatten()
+        .filter(|pair| pair.as_rule() == Rule::comment)
+        .map(|pair| {
+            let (line, column) = pair.line_col();
+            Comment {
+                text: pair.as_str().to_string(),
+                line,
+                column,
+            }
+        })
+        .collect())
+}
 
-    for pair in pairs {
-        match pair.as_rule() {
-            Rule::EOI | Rule::ret => break,
-            _ => match compile_op(pair, &mut params)? {
-                Node::Op(op) => ops.push(op),
-                Node::Json(..) => bail!("constants do nothing as a top level statement"),
-            },
+/// Everything compiling a source found, see `compile_all`
+#[derive(Debug)]
+pub struct Compilation {
+    /// The statements that compiled, broken ones are left out
+    pub plan: Plan,
+    /// In source order
+    pub errors: Vec<CompileError>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Compiles as much of the source as it can, collecting every error and
+/// warning instead of stopping at the first
+pub fn compile_all(src: &str) -> Compilation {
+    let mut state = State::default();
+
+    let ops = match PlanoutParser::parse(Rule::program, src) {
+        Ok(pairs) => {
+            let mut pairs = pairs.filter(|p| p.as_rule() != Rule::EOI).peekable();
+            while let Some(pair) =
+                pairs.next_if(|p| matches!(p.as_rule(), Rule::input | Rule::output))
+            {
+                let span = pair.as_span();
+                let compiled = match pair.as_rule() {
+                    Rule::input => compile_input(pair, &mut state),
+                    _ => compile_output(pair, &mut state),
+                };
+                if let Err(err) = compiled {
+                    state.errors.push(compile_error(span, err));
+                }
+            }
+
+            compile_stmts(pairs, &mut state)
+        }
+        // Junk soaks up anything, so this shouldn't happen
+        Err(err) => {
+            let message = expected(&err);
+            state.errors.push(CompileError::ParseError {
+                span: Diagnostic {
+                    message,
+                    ..Diagnostic::from_pest(err)
+                },
+            });
+            Vec::new()
+        }
+    };
+    let comments = compile_comments(src).unwrap_or_else(|err| {
+        state.errors.push(err);
+        Vec::new()
+    });
+
+    // With a header, every variable has to be an input or assigned before
+    // it's read
+    if !state.inputs.is_empty() {
+        for (name, range) in &state.reads {
+            if !state.inputs.iter().any(|input| &input.name == name) {
+                let span = Span::new(src, range.start, range.end).expect("inside the source");
+                state.errors.push(CompileError::UnknownVariable {
+                    name: name.clone(),
+                    span: Diagnostic::new(
+                        span,
+                        format!("{} isn't an input or assigned before it's read", name),
+                    ),
+                });
+            }
         }
     }
 
-    Ok(Plan {
-        ops,
-        params: params.into_iter().collect(),
-    })
+    let is_output = |name: &String| {
+        !state.locals.contains(name)
+            && !crate::is_local(name)
+            && state
+                .outputs
+                .as_ref()
+                .is_none_or(|outputs| outputs.iter().any(|(output, _)| output == name))
+    };
+    for (name, range) in state.outputs.iter().flatten() {
+        let problem = if !state.is_assigned(name) {
+            "is never assigned"
+        } else if state.locals.contains(name) || crate::is_local(name) {
+            "is a local"
+        } else {
+            continue;
+        };
+        let span = Span::new(src, range.start, range.end).expect("inside the source");
+        state.errors.push(CompileError::Invalid {
+            span: Diagnostic::new(span, format!("output {} {}", name, problem)),
+        });
+    }
+    let params = state
+        .params
+        .iter()
+        .filter(|param| is_output(&param.name))
+        .cloned()
+        .collect();
+
+    state
+        .errors
+        .sort_by_key(|err| err.span().map(|span| (span.line, span.column)));
+
+    Compilation {
+        plan: Plan {
+            ops,
+            params,
+            salt: crate::DEFAULT_SALT.to_string(),
+            comments,
+            inputs: state.inputs,
+        },
+        errors: state.errors,
+        warnings: state.warnings,
+    }
+}
+
+/// Compiles the source, failing with its first error
+pub fn compile(src: &str) -> std::result::Result<Plan, CompileError> {
+    let compilation = compile_all(src);
+
+    match compilation.errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(compilation.plan),
+    }
+}
+
+// A parse error's message in the language's terms rather than the
+// grammar's, each naming what was expected once
+fn expected(err: &Error<Rule>) -> String {
+    let (positives, negatives) = match &err.variant {
+        ErrorVariant::ParsingError {
+            positives,
+            negatives,
+        } => (positives, negatives),
+        ErrorVariant::CustomError { message } => return message.clone(),
+    };
+
+    // a, a or b, a, b, or c
+    let list = |rules: &[Rule]| {
+        let mut names: Vec<String> = Vec::new();
+        for name in rules.iter().map(describe) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        match names.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, [first])) => format!("{} or {}", first, last),
+            Some((last, rest)) => format!("{}, or {}", rest.join(", "), last),
+            None => String::new(),
+        }
+    };
+
+    match (negatives.is_empty(), positives.is_empty()) {
+        (false, false) => format!(
+            "unexpected {}; expected {}",
+            list(negatives),
+            list(positives)
+        ),
+        (false, true) => format!("unexpected {}", list(negatives)),
+        (true, false) => format!("expected {}", list(positives)),
+        (true, true) => "unknown parsing error".to_string(),
+    }
+}
+
+// How parse errors refer to rules, i.e. what was expected
+fn describe(rule: &Rule) -> String {
+    let token = match rule {
+        Rule::op_bool_or => "||",
+        Rule::op_bool_and => "&&",
+        Rule::op_eq => "==",
+        Rule::op_ne => "!=",
+        Rule::op_ge => ">=",
+        Rule::op_le => "<=",
+        Rule::op_gt => ">",
+        Rule::op_lt => "<",
+        Rule::op_add => "+",
+        Rule::op_sub | Rule::op_neg => "-",
+        Rule::op_mul => "*",
+        Rule::op_div => "/",
+        Rule::op_mod => "%",
+        Rule::op_bool_not => "!",
+        Rule::op_assign => "=",
+        Rule::op_arrow => "=>",
+        Rule::op_access | Rule::field_access => ".",
+        Rule::index | Rule::array_start | Rule::array => "[",
+        Rule::array_end => "]",
+        Rule::block_start | Rule::nested | Rule::map => "{",
+        Rule::block_end | Rule::stray => "}",
+        Rule::semi => ";",
+        Rule::optional => "?",
+        Rule::op_return | Rule::ret => "return",
+        Rule::op_if | Rule::conditional => "if",
+        Rule::op_else_if => "else if",
+        Rule::op_else => "else",
+        Rule::op_switch | Rule::switch => "switch",
+        Rule::op_let => "let",
+        Rule::op_input | Rule::input => "input",
+        Rule::op_output | Rule::output => "output",
+        Rule::null => "null",
+        Rule::boolean => return "`true` or `false`".to_string(),
+        Rule::EOI => return "end of input".to_string(),
+        Rule::ident | Rule::call => return "identifier".to_string(),
+        Rule::input_type => return "type".to_string(),
+        Rule::number | Rule::decimal | Rule::integer => return "number".to_string(),
+        Rule::string | Rule::string_inner => return "string".to_string(),
+        Rule::assignment | Rule::bare | Rule::junk => return "statement".to_string(),
+        Rule::expr | Rule::operation => return "expression".to_string(),
+        Rule::call_arg => return "argument".to_string(),
+        Rule::map_entry => return "map entry".to_string(),
+        Rule::switch_arm => return "switch arm".to_string(),
+        rule => return format!("{:?}", rule),
+    };
+
+    format!("`{}`", token)
+}
+
+/// Compiles a file, naming it in diagnostics
+pub fn compile_file(path: impl AsRef<Path>) -> std::result::Result<Plan, CompileError> {
+    let file = path.as_ref().display().to_string();
+    let src = fs::read_to_string(&path).map_err(|error| CompileError::Io {
+        file: file.clone(),
+        error,
+    })?;
+
+    compile(&src).map_err(|err| err.file(&file))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{compile, compile_all, compile_file};
+    use crate::ir::Op;
+    use crate::{CompileError, Input, InputType, Param};
+    use serde_json::json;
+
+    fn assert_ops(src: &str, expected: serde_json::Value) {
+        let expected: Vec<Op> = serde_json::from_value(expected).unwrap();
+        assert_eq!(compile(src).unwrap().ops, expected);
+    }
+
+    fn assert_compile_err(src: &str, expected: &str) {
+        match compile(src) {
+            Ok(..) => panic!("expected {} to fail compiling", src),
+            Err(err) => match err.span() {
+                Some(span) => assert_eq!(span.message, expected),
+                None => panic!("{} failed outside the source: {}", src, err),
+            },
+        }
+    }
+
+    #[test]
+    fn test_call_named_args() {
+        assert_ops(
+            r#"
+            group_size = uniformChoice(choices=[1, 10], unit=userid);
+            specific_goal = bernoulliTrial(p=0.8, unit=userid);
+            "#,
+            json!([
+                {
+                    "op": "set",
+                    "var": "group_size",
+                    "value": {
+                        "op": "uniformChoice",
+                        "choices": {"op": "array", "values": [1, 10]},
+                        "unit": {"op": "get", "var": "userid"}
+                    }
+                },
+                {
+                    "op": "set",
+                    "var": "specific_goal",
+                    "value": {
+                        "op": "bernoulliTrial",
+                        "p": 0.8,
+                        "unit": {"op": "get", "var": "userid"}
+                    }
+                }
+            ]),
+        )
+    }
+
+    #[test]
+    fn test_call_salts() {
+        assert_ops(
+            r#"x = randomFloat(min=0, max=1, unit=userid, salt="s", full_salt="f");"#,
+            json!([{
+                "op": "set",
+                "var": "x",
+                "value": {
+                    "op": "randomFloat",
+                    "min": 0,
+                    "max": 1,
+                    "unit": {"op": "get", "var": "userid"},
+                    "salt": "s",
+                    "full_salt": "f"
+                }
+            }]),
+        )
+    }
+
+    #[test]
+    fn test_index() {
+        assert_ops(
+            r#"x = user.devices[i];"#,
+            json!([{
+                "op": "set",
+                "var": "x",
+                "value": {
+                    "op": "index",
+                    "base": {
+                        "op": "index",
+                        "base": {"op": "get", "var": "user"},
+                        "index": "devices"
+                    },
+                    "index": {"op": "get", "var": "i"}
+                }
+            }]),
+        )
+    }
+
+    #[test]
+    fn test_map() {
+        assert_ops(
+            r#"x = {color: "red", "font-size": 12, nested: {}, n: i + 1,};"#,
+            json!([{
+                "op": "set",
+                "var": "x",
+                "value": {
+                    "op": "map",
+                    "color": "red",
+                    "font-size": 12,
+                    "nested": {"op": "map"},
+                    "n": {"op": "sum", "values": [{"op": "get", "var": "i"}, 1]}
+                }
+            }]),
+        );
+        assert_compile_err("x = {a: 1, a: 2};", "map key `a` is repeated");
+        assert_compile_err("x = {op: 1};", "`op` can't be used as a map key");
+    }
+
+    #[test]
+    fn test_comments() {
+        let src = r##"# Owned by the growth team
+            x = 1; // half the traffic
+            // "quoted" in a comment
+            y = "# not a comment" + x; # trailing
+            z = x // not x / y
+                ;
+        "##;
+
+        assert_ops(
+            src,
+            json!([
+                {"op": "set", "var": "x", "value": 1},
+                {"op": "set", "var": "y", "value": {
+                    "op": "sum",
+                    "values": ["# not a comment", {"op": "get", "var": "x"}]
+                }},
+                {"op": "set", "var": "z", "value": {"op": "get", "var": "x"}}
+            ]),
+        );
+
+        let plan = compile(src).unwrap();
+        let comments = plan
+            .comments()
+            .iter()
+            .map(|c| (c.text.as_str(), c.line, c.column))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            comments,
+            vec![
+                ("# Owned by the growth team", 1, 1),
+                ("// half the traffic", 2, 20),
+                ("// \"quoted\" in a comment", 3, 13),
+                ("# trailing", 4, 40),
+                ("// not x / y", 5, 19),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_switch() {
+        assert_ops(
+            r#"
+            switch {
+                country == "US" => color = "red";
+                country == "CA" => { color = "blue"; size = 2; }
+                true => color = "green";
+            }
+            "#,
+            json!([{
+                "op": "cond",
+                "cond": [
+                    {
+                        "if": {"op": "equals", "left": {"op": "get", "var": "country"}, "right": "US"},
+                        "then": {"op": "set", "var": "color", "value": "red"}
+                    },
+                    {
+                        "if": {"op": "equals", "left": {"op": "get", "var": "country"}, "right": "CA"},
+                        "then": {"op": "seq", "seq": [
+                            {"op": "set", "var": "color", "value": "blue"},
+                            {"op": "set", "var": "size", "value": 2}
+                        ]}
+                    },
+                    {
+                        "if": true,
+                        "then": {"op": "set", "var": "color", "value": "green"}
+                    }
+                ]
+            }]),
+        );
+
+        // An arm can be any statement
+        assert_ops(
+            "switch { a => return false; b => coalesce(x, 1); true => x = 1; }",
+            json!([{
+                "op": "cond",
+                "cond": [
+                    {"if": {"op": "get", "var": "a"}, "then": {"op": "return", "value": false}},
+                    {"if": {"op": "get", "var": "b"}, "then": {"op": "coalesce", "values": [{"op": "get", "var": "x"}, 1]}},
+                    {"if": true, "then": {"op": "set", "var": "x", "value": 1}}
+                ]
+            }]),
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        assert_ops(
+            "returning = 1; if (returning) { return false } return returning;",
+            json!([
+                {"op": "set", "var": "returning", "value": 1},
+                {"op": "cond", "cond": [{
+                    "if": {"op": "get", "var": "returning"},
+                    "then": {"op": "return", "value": false}
+                }]},
+                {"op": "return", "value": {"op": "get", "var": "returning"}}
+            ]),
+        )
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert_ops(
+            "if (a) {} { x = 1; { y = 2; } } if (b) { if (c) { return d; } else { {} } }",
+            json!([
+                {"op": "cond", "cond": [{
+                    "if": {"op": "get", "var": "a"},
+                    "then": {"op": "seq", "seq": []}
+                }]},
+                {"op": "seq", "seq": [
+                    {"op": "set", "var": "x", "value": 1},
+                    {"op": "set", "var": "y", "value": 2}
+                ]},
+                {"op": "cond", "cond": [{
+                    "if": {"op": "get", "var": "b"},
+                    "then": {"op": "cond", "cond": [
+                        {
+                            "if": {"op": "get", "var": "c"},
+                            "then": {"op": "return", "value": {"op": "get", "var": "d"}}
+                        },
+                        {"if": true, "then": {"op": "seq", "seq": []}}
+                    ]}
+                }]}
+            ]),
+        );
+
+        // A `{` starting a statement is a block, not a map
+        assert_compile_err(
+            "{ a: 1 };",
+            "expected `=`, `[`, `||`, `&&`, `==`, `!=`, `>=`, `<=`, `>`, `<`, `+`, `-`, \
+             `*`, `/`, `%`, `.`, or `;`",
+        );
+    }
+
+    #[test]
+    fn test_inputs() {
+        // Call arguments, fields and map keys aren't variables
+        compile(
+            "input userid: string;\n\
+             input user: map;\n\
+             x = bernoulliTrial(p=0.5, unit=userid);\n\
+             y = {a: user.country, b: x};",
+        )
+        .expect("compile ok");
+        assert_compile_err(
+            "input userid: string;\nx = uniformChoice(choices=[1, 2], unit=userid);\ny = user.country;",
+            "user isn't an input or assigned before it's read",
+        );
+        // Reads are checked in source order, a later assignment doesn't count
+        assert_compile_err(
+            "input u: string; x = y; y = 1;",
+            "y isn't an input or assigned before it's read",
+        );
+        assert_compile_err(
+            "input u: string; x = x;",
+            "x isn't an input or assigned before it's read",
+        );
+        compile("input u: string; let y = u; x = y;").expect("compile ok");
+        let plan = compile(
+            "input userid: string;\n\
+             input age: number?;\n\
+             x = uniformChoice(choices=[1, 2], unit=userid);\n\
+             if (age > 18) { y = x; } z = y;",
+        )
+        .expect("compile ok");
+        assert_eq!(
+            plan.inputs(),
+            [
+                Input {
+                    name: "userid".to_string(),
+                    ty: InputType::String,
+                    optional: false
+                },
+                Input {
+                    name: "age".to_string(),
+                    ty: InputType::Number,
+                    optional: true
+                }
+            ]
+        );
+
+        // Without a header anything unassigned is an input
+        assert!(compile("x = userid;").unwrap().inputs().is_empty());
+
+        assert_compile_err(
+            "input n: int;",
+            "unknown input type int, expected string, number, boolean, array or map",
+        );
+        assert_compile_err(
+            "input n: number; input n: string;",
+            "input n is already declared",
+        );
+        assert_compile_err(
+            "input n: number; x = n; input m: number;",
+            "inputs are declared at the top of the plan, before any statement",
+        );
+
+        // Unknown variables are reported in order with the rest
+        let errors = compile_all("input a: number;\nb = c;\nd = (;\ne = f;")
+            .errors
+            .iter()
+            .map(|err| {
+                let span = err.span().unwrap();
+                (
+                    span.line,
+                    matches!(err, CompileError::UnknownVariable { .. }),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![(2, true), (3, false), (4, true)]);
+    }
+
+    #[test]
+    fn test_locals() {
+        let params = |src: &str| {
+            let plan = compile(src).expect("compile ok");
+            plan.params()
+                .iter()
+                .map(|param| param.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            params("let base_price = 10; _discount = 0.5; price = base_price * _discount;"),
+            vec!["price"]
+        );
+        // A local stays one however else it's assigned
+        assert_eq!(params("let x = 1; if (a) { x = 2; } y = x;"), vec!["y"]);
+        // `let` on its own is still a variable name
+        assert_eq!(params("let = 1;"), vec!["let"]);
+
+        assert_eq!(
+            params("output price, color; base = 10; price = base * 2; color = \"red\";"),
+            vec!["price", "color"]
+        );
+        assert_eq!(
+            params("output a; output b; a = 1; b = 2; c = 3;"),
+            vec!["a", "b"]
+        );
+
+        assert_compile_err("output price; x = 1;", "output price is never assigned");
+        assert_compile_err("output _x; _x = 1;", "output _x is a local");
+        assert_compile_err("output x, x; x = 1;", "output x is already listed");
+        assert_compile_err(
+            "x = 1; output x;",
+            "outputs are declared at the top of the plan, before any statement",
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        let plan = compile(
+            "zeta = 1;\n\
+             if (a) {\n  \
+               alpha = 2;\n  \
+               zeta = 3;\n\
+             }\n\
+             switch {\n  \
+               b => mid = 4;\n  \
+               true => { alpha = 5; }\n\
+             }\n\
+             alpha = 6;\n\
+             let beta = 7;",
+        )
+        .expect("compile ok");
+
+        let param = |name: &str, line, column, span, conditional| Param {
+            name: name.to_string(),
+            line,
+            column,
+            span,
+            conditional,
+        };
+        assert_eq!(
+            plan.params(),
+            [
+                param("zeta", 1, 1, 0..9, false),
+                param("alpha", 3, 3, 21..31, false),
+                param("mid", 7, 8, 62..70, true),
+            ]
+        );
+
+        // A return might stop the plan before later assignments, and
+        // nothing after one in the same block is ever assigned
+        let plan = compile(
+            "if (a) { return false; y = 1; } x = 1;\n\
+             z = 1; return 1; w = 2; { v = 3; }",
+        )
+        .unwrap();
+        assert_eq!(
+            plan.params(),
+            [
+                param("x", 1, 33, 32..38, true),
+                param("z", 2, 1, 39..45, true)
+            ]
+        );
+
+        // The same every time, whatever the hashing
+        for _ in 0..10 {
+            let again = compile("c = 1; b = 2; a = 3; d = c;").unwrap();
+            let names = again.params().iter().map(|param| param.name.as_str());
+            assert_eq!(names.collect::<Vec<_>>(), ["c", "b", "a", "d"]);
+        }
+    }
+
+    #[test]
+    fn test_keywords() {
+        let err = |src: &str| compile(src).unwrap_err().to_string();
+
+        // return needs a value, it isn't a variable
+        assert_eq!(err("return;"), "1:7: expected expression");
+        assert_eq!(
+            err("x = 1;\nif (a) { return; }"),
+            "2:16: expected expression"
+        );
+        assert_eq!(err("return = 1;"), "1:8: expected expression");
+        assert_eq!(err("x = if;"), "1:5: expected expression");
+        assert!(compile("if = 2;").is_err());
+        assert!(compile("x = {switch: 1};").is_err());
+
+        // Only whole words are keywords
+        let plan =
+            compile("switchy = 1; iffy = 2; elsewhere = 3; nullable = 4; returned = 5;").unwrap();
+        assert_eq!(plan.params().len(), 5);
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        assert_ops(
+            r#"
+            a = "line\nbreak\ttab \"quoted\" back\\slash \/ \b\f\r";
+            b = "caf\u00e9 café";
+            c = "\ud83d\ude00 😀";
+            "#,
+            json!([
+                {"op": "set", "var": "a", "value": "line\nbreak\ttab \"quoted\" back\\slash / \u{8}\u{c}\r"},
+                {"op": "set", "var": "b", "value": "café café"},
+                {"op": "set", "var": "c", "value": "😀 😀"}
+            ]),
+        );
+
+        // Every string comes back out of the IR exactly as written
+        let plan = compile(r#"x = ["é\n", "\ud83d\ude00", "\\u0041"];"#).unwrap();
+        let json = serde_json::to_string(&plan.ops).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Op>>(&json).unwrap(), plan.ops,);
+        assert!(json.contains(r#"["é\n","😀","\\u0041"]"#), "{}", json);
+
+        let compile_err = |src| compile(src).unwrap_err().to_string();
+        assert_eq!(compile_err(r#"x = "a\qb";"#), r#"1:7: invalid escape `\q`"#);
+        assert_eq!(
+            compile_err("x = 1;\ny = \"é\\u00G1\";"),
+            r#"2:7: invalid escape `\u00G1`"#
+        );
+        assert_eq!(
+            compile_err(r#"x = "\u12";"#),
+            r#"1:6: invalid escape `\u12`"#
+        );
+        // Surrogates have to come in pairs
+        assert_eq!(
+            compile_err(r#"x = "\ud83d";"#),
+            r#"1:6: invalid escape `\ud83d`"#
+        );
+        assert_eq!(
+            compile_err(r#"x = "\ud83dx";"#),
+            r#"1:6: invalid escape `\ud83d`"#
+        );
+        assert_eq!(
+            compile_err(r#"x = "\ude00";"#),
+            r#"1:6: invalid escape `\ude00`"#
+        );
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert_ops(
+            r#"
+            a = [-3, 1_000_000, 2.5, .5, 1e-3, 2.5E+3, 1., -0.25];
+            b = [9223372036854775807, 18446744073709551615, -9223372036854775808];
+            c = x -3;
+            d = --3;
+            e = -x;
+            "#,
+            json!([
+                {"op": "set", "var": "a", "value": {"op": "array", "values": [
+                    -3, 1000000, 2.5, 0.5, 0.001, 2500.0, 1.0, -0.25
+                ]}},
+                {"op": "set", "var": "b", "value": {"op": "array", "values": [
+                    i64::MAX, u64::MAX, i64::MIN
+                ]}},
+                {"op": "set", "var": "c", "value": {"op": "sum", "values": [
+                    {"op": "get", "var": "x"},
+                    {"op": "negative", "value": 3}
+                ]}},
+                {"op": "set", "var": "d", "value": {"op": "negative", "value": -3}},
+                {"op": "set", "var": "e", "value": {"op": "negative", "value": {"op": "get", "var": "x"}}}
+            ]),
+        );
+
+        let plan = compile("x = [1, 1.0, 1e0];").unwrap();
+        let json = serde_json::to_string(&plan.ops).unwrap();
+        assert!(json.contains("[1,1.0,1.0]"), "{}", json);
+
+        assert_compile_err(
+            "x = 18446744073709551616;",
+            "integer literal 18446744073709551616 is out of range",
+        );
+        assert_compile_err(
+            "x = -9223372036854775809;",
+            "integer literal -9223372036854775809 is out of range",
+        );
+        assert_compile_err("x = 1e400;", "decimal literal 1e400 is out of range");
+        assert_compile_err("x = 1e-400;", "decimal literal 1e-400 is out of range");
+        assert_ops(
+            "x = 0e-400; y = 0.0;",
+            json!([
+                {"op": "set", "var": "x", "value": 0.0},
+                {"op": "set", "var": "y", "value": 0.0}
+            ]),
+        );
+        assert!(compile("x = 1__000;").is_err());
+        assert!(compile("x = _1;").is_err());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let diagnostic = |src: &str| {
+            compile(src)
+                .unwrap_err()
+                .span()
+                .cloned()
+                .expect("a diagnostic")
+        };
+
+        // The innermost pair that failed is underlined
+        let d = diagnostic("x = 1;\nif (x) {\n  y = [1, coinFlip(unit=userid)];\n}\n");
+        assert_eq!(
+            (d.line, d.column, d.width, d.excerpt.as_str()),
+            (3, 11, 21, "  y = [1, coinFlip(unit=userid)];")
+        );
+        assert_eq!(d.to_string(), "3:11: unknown function coinFlip");
+
+        // Parse errors too
+        let d = diagnostic("x = 1;\ny = (2;\n");
+        assert_eq!((d.line, d.column, d.excerpt.as_str()), (2, 7, "y = (2;"));
+        assert_eq!(
+            d.message,
+            "expected `[`, `||`, `&&`, `==`, `!=`, `>=`, `<=`, `>`, `<`, `+`, `-`, `*`, `/`, \
+             `%`, or `.`"
+        );
+
+        // Rules are named the way the language calls them
+        let d = diagnostic("x = 1 +;");
+        assert_eq!(
+            d.message,
+            "expected `[`, `{`, `-`, `!`, `true` or `false`, `null`, identifier, number, or string"
+        );
+        let d = diagnostic("x = 1;\n+ 1;");
+        assert_eq!(
+            d.message,
+            "expected statement, expression, `return`, `switch`, `if`, or `{`"
+        );
+
+        let d = diagnostic("x = 1;\n  \"constant\";");
+        assert_eq!(
+            d.render(),
+            "error: constants do nothing as a statement\n \
+             --> 2:3\n  \
+             |\n\
+             2 |   \"constant\";\n  \
+             |   ^^^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_all() {
+        let src = "a = 1;\n\
+                   b = (2;\n\
+                   if (a) {\n  \
+                     c = coinFlip(unit=userid);\n  \
+                     d = 3;\n\
+                   }\n\
+                   e = 4 +;\n\
+                   return true;\n\
+                   f = 5;\n";
+        let compilation = compile_all(src);
+
+        let errors = compilation
+            .errors
+            .iter()
+            .map(|err| {
+                let span = err.span().unwrap();
+                (span.line, span.column)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![(2, 7), (4, 7), (7, 8)]);
+
+        let warnings = &compilation.warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, crate::Severity::Warning);
+        assert_eq!(warnings[0].message, "unreachable statement after return");
+        assert_eq!((warnings[0].line, warnings[0].column), (9, 1));
+
+        // One warning per unreachable stretch
+        let warnings = compile_all("x = 1; return 1; y = 2; return 3; z = 4;").warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].line, warnings[0].column), (1, 18));
+
+        // Everything that compiled is kept, f is after the return
+        let params = compilation.plan.params().iter().map(|param| &param.name);
+        assert_eq!(params.collect::<Vec<_>>(), vec!["a", "d"]);
+
+        // A broken statement's block is skipped with it, its "}" isn't stray
+        let compilation = compile_all("a = 1;\nif (a {\n  b = 2;\n}\nc = 3;");
+        assert_eq!(compilation.errors.len(), 1);
+        assert_eq!(compilation.errors[0].span().unwrap().line, 2);
+        assert_eq!(compilation.plan.ops.len(), 2);
+
+        // Stray closing braces are skipped over too
+        let compilation = compile_all("a = 1;\n}\nb = 2;");
+        assert_eq!(compilation.errors.len(), 1);
+        assert_eq!(compilation.plan.ops.len(), 2);
+
+        // Nothing to report
+        let compilation = compile_all("a = 1;");
+        assert!(compilation.errors.is_empty() && compilation.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_compile_file() {
+        let path = std::env::temp_dir().join(format!("planout-{}.planout", std::process::id()));
+        std::fs::write(&path, "x = 1;\ny = coinFlip(unit=userid);\n").unwrap();
+        let err = compile_file(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(err.span().unwrap().file, Some(path.display().to_string()));
+        assert_eq!(
+            err.to_string(),
+            format!("{}:2:5: unknown function coinFlip", path.display())
+        );
+
+        assert!(matches!(
+            compile_file(&path),
+            Err(CompileError::Io { file, .. }) if file == path.display().to_string()
+        ));
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let err = |src| compile(src).unwrap_err();
+
+        assert!(matches!(err("x = (1;"), CompileError::ParseError { .. }));
+        assert!(matches!(
+            err(r#"x = "\q";"#),
+            CompileError::ParseError { .. }
+        ));
+        assert!(matches!(
+            err("x = 99999999999999999999;"),
+            CompileError::ParseError { .. }
+        ));
+        assert!(matches!(
+            err("x = coinFlip(unit=userid);"),
+            CompileError::UnknownOperator { name, .. } if name == "coinFlip"
+        ));
+        assert!(matches!(
+            err("x = uniformChoice(choices=[1, coinFlip(unit=userid)], unit=userid);"),
+            CompileError::UnknownOperator { name, .. } if name == "coinFlip"
+        ));
+        assert!(matches!(
+            err("x = bernoulliTrial(p=0.5);"),
+            CompileError::InvalidArguments { name, .. } if name == "bernoulliTrial"
+        ));
+        assert!(matches!(
+            err("x = {a: 1, a: 2};"),
+            CompileError::Invalid { .. }
+        ));
+    }
+
+    #[test]
+    fn test_call_errors() {
+        assert_compile_err(
+            "x = uniformChoice(choices=[1, 2]);",
+            "uniformChoice is missing required argument `unit`",
+        );
+        assert_compile_err(
+            "x = bernoulliTrial(p=0.5, unit=userid, weights=[1]);",
+            "bernoulliTrial got an unexpected argument `weights`",
+        );
+        assert_compile_err(
+            "x = bernoulliTrial(p=0.5, p=0.2, unit=userid);",
+            "bernoulliTrial got argument `p` more than once",
+        );
+        assert_compile_err("x = coinFlip(unit=userid);", "unknown function coinFlip");
+        assert_compile_err(
+            "x = uniformChoice([1, 2], unit=userid);",
+            "uniformChoice only takes named arguments",
+        );
+        assert_compile_err(
+            "x = coalesce(values=[1], 2);",
+            "coalesce got a positional argument after a named one",
+        );
+        assert_compile_err(
+            "x = isNull(a, b);",
+            "isNull takes exactly one argument, found 2",
+        );
+    }
+
+    #[test]
+    fn test_null_and_coalesce() {
+        assert_ops(
+            "x = coalesce(age, null, nullable == 1); y = isNull(x);",
+            json!([
+                {
+                    "op": "set",
+                    "var": "x",
+                    "value": {"op": "coalesce", "values": [
+                        {"op": "get", "var": "age"},
+                        null,
+                        {"op": "equals", "left": {"op": "get", "var": "nullable"}, "right": 1}
+                    ]}
+                },
+                {
+                    "op": "set",
+                    "var": "y",
+                    "value": {"op": "equals", "left": {"op": "get", "var": "x"}, "right": null}
+                }
+            ]),
+        )
+    }
+
+    #[test]
+    fn test_keyword_prefixed_idents() {
+        assert_ops(
+            "x = true_count + 1; y = falsey; z = nullable;",
+            json!([
+                {"op": "set", "var": "x", "value": {"op": "sum", "values": [
+                    {"op": "get", "var": "true_count"},
+                    1
+                ]}},
+                {"op": "set", "var": "y", "value": {"op": "get", "var": "falsey"}},
+                {"op": "set", "var": "z", "value": {"op": "get", "var": "nullable"}}
+            ]),
+        )
+    }
+
     //use crate::ir::Op;
     //use crate::{eval::evaluate, Variable, Variables};
     //use std::str::FromStr;
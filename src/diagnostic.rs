@@ -0,0 +1,194 @@
+//! Errors pointing into PlanOut source, rendered like rustc's for people
+//! and as JSON for tools.
+use pest::{
+    error::{Error, LineColLocation},
+    RuleType, Span,
+};
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Set when compiling a file, see `compile_file`
+    pub file: Option<String>,
+    pub message: String,
+    /// 1-based, like editors
+    pub line: usize,
+    pub column: usize,
+    /// The source line the diagnostic starts on
+    pub excerpt: String,
+    /// How many characters of the excerpt to underline, at least 1
+    pub width: usize,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(span: Span, message: impl Into<String>) -> Diagnostic {
+        let start = span.start_pos();
+        let (line, column) = start.line_col();
+        let excerpt = start.line_of().trim_end_matches(['\r', '\n']).to_string();
+
+        // Multi-line spans are underlined to the end of their first line
+        let rest = excerpt.chars().count().saturating_sub(column - 1);
+        let width = span.as_str().chars().take_while(|c| *c != '\n').count();
+
+        Diagnostic {
+            severity: Severity::Error,
+            file: None,
+            message: message.into(),
+            line,
+            column,
+            excerpt,
+            width: width.min(rest).max(1),
+        }
+    }
+
+    pub(crate) fn from_pest<R: RuleType>(err: Error<R>) -> Diagnostic {
+        let (line, column) = match err.line_col {
+            LineColLocation::Pos(pos) | LineColLocation::Span(pos, _) => pos,
+        };
+        let width = match err.line_col {
+            LineColLocation::Span((l1, c1), (l2, c2)) if l1 == l2 => c2.saturating_sub(c1),
+            _ => 1,
+        };
+
+        Diagnostic {
+            severity: Severity::Error,
+            file: None,
+            message: err.variant.message().into_owned(),
+            line,
+            column,
+            excerpt: err.line().to_string(),
+            width: width.max(1),
+        }
+    }
+
+    pub(crate) fn warning(self) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..self
+        }
+    }
+
+    /// Names the file the source came from
+    pub fn file(self, file: &str) -> Diagnostic {
+        Diagnostic {
+            file: Some(file.to_string()),
+            ..self
+        }
+    }
+
+    /// The diagnostic with its excerpt and a caret underline, e.g.
+    ///
+    /// ```text
+    /// error: unknown function coinFlip
+    ///  --> exp.planout:3:5
+    ///   |
+    /// 3 | x = coinFlip(unit=userid);
+    ///   |     ^^^^^^^^^^^^^^^^^^^^^
+    /// ```
+    pub fn render(&self) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Tabs are kept so the caret lines up however they're displayed
+        let indent = self
+            .excerpt
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+
+        format!(
+            "{}: {}\n{}--> {}\n{} |\n{} | {}\n{} | {}{}\n",
+            severity,
+            self.message,
+            gutter,
+            self.location(),
+            gutter,
+            self.line,
+            self.excerpt,
+            gutter,
+            indent,
+            "^".repeat(self.width)
+        )
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("diagnostics serialize")
+    }
+
+    fn location(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}:{}", file, self.line, self.column),
+            None => format!("{}:{}", self.line, self.column),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location(), self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pest::Span;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let src = "x = 1;\ny = coinFlip(unit=userid);\n";
+        let span = Span::new(src, 11, 33).unwrap();
+        let diagnostic = Diagnostic::new(span, "unknown function coinFlip").file("exp.planout");
+
+        assert_eq!(
+            diagnostic.render(),
+            "error: unknown function coinFlip\n \
+             --> exp.planout:2:5\n  \
+             |\n\
+             2 | y = coinFlip(unit=userid);\n  \
+             |     ^^^^^^^^^^^^^^^^^^^^^^\n"
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            "exp.planout:2:5: unknown function coinFlip"
+        );
+        assert_eq!(
+            diagnostic.to_json(),
+            json!({
+                "severity": "error",
+                "file": "exp.planout",
+                "message": "unknown function coinFlip",
+                "line": 2,
+                "column": 5,
+                "excerpt": "y = coinFlip(unit=userid);",
+                "width": 22,
+            })
+        );
+    }
+
+    #[test]
+    fn test_multi_line_span() {
+        let src = "if (x) {\n  y = 1;\n}";
+        let diagnostic = Diagnostic::new(Span::new(src, 0, src.len()).unwrap(), "oops");
+
+        assert_eq!((diagnostic.line, diagnostic.column), (1, 1));
+        assert_eq!(diagnostic.width, 8);
+        assert_eq!(diagnostic.to_string(), "1:1: oops");
+    }
+}
@@ -0,0 +1,102 @@
+//! The errors `compile` and `evaluate` return, so callers can decide
+//! per kind whether to fail open or closed.
+use crate::Diagnostic;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CompileError {
+    /// The source isn't valid PlanOut, including malformed literals
+    ParseError { span: Diagnostic },
+    /// A call to a function PlanOut doesn't have
+    UnknownOperator { name: String, span: Diagnostic },
+    /// A read of a variable the plan can't know about
+    UnknownVariable { name: String, span: Diagnostic },
+    /// Missing, repeated or unexpected call arguments
+    InvalidArguments { name: String, span: Diagnostic },
+    /// Anything else that parses but doesn't make a plan
+    Invalid { span: Diagnostic },
+    /// `compile_file` couldn't read the file
+    Io { file: String, error: std::io::Error },
+}
+
+impl CompileError {
+    /// Where in the source the error is, if it's in the source
+    pub fn span(&self) -> Option<&Diagnostic> {
+        match self {
+            CompileError::ParseError { span }
+            | CompileError::UnknownOperator { span, .. }
+            | CompileError::UnknownVariable { span, .. }
+            | CompileError::InvalidArguments { span, .. }
+            | CompileError::Invalid { span } => Some(span),
+            CompileError::Io { .. } => None,
+        }
+    }
+
+    /// Names the file the source came from
+    pub(crate) fn file(mut self, file: &str) -> CompileError {
+        match &mut self {
+            CompileError::ParseError { span }
+            | CompileError::UnknownOperator { span, .. }
+            | CompileError::UnknownVariable { span, .. }
+            | CompileError::InvalidArguments { span, .. }
+            | CompileError::Invalid { span } => span.file = Some(file.to_string()),
+            CompileError::Io { .. } => (),
+        }
+
+        self
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::ParseError { span }
+            | CompileError::UnknownOperator { span, .. }
+            | CompileError::UnknownVariable { span, .. }
+            | CompileError::InvalidArguments { span, .. }
+            | CompileError::Invalid { span } => span.fmt(f),
+            CompileError::Io { file, error } => write!(f, "reading {}: {}", file, error),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EvalError {
+    /// A variable the plan needs isn't set
+    UnknownVariable { name: String },
+    /// An operator got a value it isn't defined for, e.g. `"a" * 2`
+    TypeMismatch { message: String },
+    /// The plan has an operator the evaluator doesn't implement
+    UnknownOperator { op: String },
+    /// A value of the right type that still can't be used,
+    /// e.g. dividing by zero or a probability above 1
+    InvalidValue { message: String },
+    /// An override for something the plan doesn't assign
+    OverrideNotAParam { name: String },
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::UnknownVariable { name } => write!(f, "{} isn't set", name),
+            EvalError::TypeMismatch { message } | EvalError::InvalidValue { message } => {
+                f.write_str(message)
+            }
+            EvalError::UnknownOperator { op } => write!(f, "unknown operator {}", op),
+            EvalError::OverrideNotAParam { name } => {
+                write!(f, "expected to override {} but it wasn't a parameter", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
@@ -1,128 +1,598 @@
+use crate::error::EvalError;
 use crate::ir::*;
+use crate::number;
+use crate::random;
 use crate::Plan;
 use crate::Variables;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 
-pub(crate) fn evaluate_op<'p>(
-    vars: &'p mut Variables,
-    op: &'p Op,
-) -> anyhow::Result<serde_json::Value> {
+type Result<T> = std::result::Result<T, EvalError>;
+
+// anyhow's bail! and ensure!, for values of the wrong type
+// and values that are the right type but unusable
+macro_rules! mismatch {
+    ($($arg:tt)*) => {
+        return Err(EvalError::TypeMismatch { message: format!($($arg)*) })
+    };
+}
+
+macro_rules! ensure_valid {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            return Err(invalid(format!($($arg)*)));
+        }
+    };
+}
+
+// serde_json would quietly turn an infinite or NaN float into null
+fn finite(n: number::Number, op: &str) -> Result<Value> {
+    match n {
+        number::Number::F64(f) if !f.is_finite() => Err(invalid(format!("{} overflowed", op))),
+        n => Ok(n.into()),
+    }
+}
+
+fn invalid(message: impl Into<String>) -> EvalError {
+    EvalError::InvalidValue {
+        message: message.into(),
+    }
+}
+
+// Evaluation state, the "mapper" in planout-py
+pub(crate) struct Env<'v> {
+    pub(crate) vars: &'v mut Variables,
+    // Prefixed to every random op's hash
+    pub(crate) experiment_salt: String,
+    // Set by `return`, whether the unit is in the experiment
+    pub(crate) returned: Option<bool>,
+    // What the plan set, inputs of the same name aside
+    pub(crate) assigned: HashSet<String>,
+}
+
+// A plan's parameters, and whether the unit is in the experiment,
+// which decides if the assignment should be logged as an exposure.
+// Like planout-py, units are in the experiment unless the plan
+// returns something falsy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    pub params: Value,
+    pub in_experiment: bool,
+}
+
+// Python truthiness, which is what the reference interpreter
+// uses for `not` and friends
+pub(crate) fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+    }
+}
+
+// JSON equality, except numbers compare by value so `1 == 1.0`, and like
+// Python a bool is the number 0 or 1 so `true == 1`
+fn values_eq(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => {
+            number::Number::from(l.clone()) == number::Number::from(r.clone())
+        }
+        (Value::Bool(b), Value::Number(n)) | (Value::Number(n), Value::Bool(b)) => {
+            number::Number::I64((*b).into()) == number::Number::from(n.clone())
+        }
+        (Value::Array(l), Value::Array(r)) => {
+            l.len() == r.len() && l.iter().zip(r).all(|(l, r)| values_eq(l, r))
+        }
+        (Value::Object(l), Value::Object(r)) => {
+            l.len() == r.len()
+                && l.iter()
+                    .all(|(k, l)| r.get(k).is_some_and(|r| values_eq(l, r)))
+        }
+        (l, r) => l == r,
+    }
+}
+
+// None if either side is null
+fn numbers(
+    env: &mut Env,
+    left: &Node,
+    right: &Node,
+    what: &str,
+) -> Result<Option<(number::Number, number::Number)>> {
+    match (evaluate_node(env, left)?, evaluate_node(env, right)?) {
+        (Value::Number(l), Value::Number(r)) => Ok(Some((l.into(), r.into()))),
+        (Value::Null, _) | (_, Value::Null) => Ok(None),
+        _ => mismatch!("{} is only defined for numbers", what),
+    }
+}
+
+fn array_arg(env: &mut Env, node: &Node, what: &str) -> Result<Vec<Value>> {
+    match evaluate_node(env, node)? {
+        Value::Array(values) => Ok(values),
+        _ => mismatch!("{} must be an array", what),
+    }
+}
+
+fn float_arg(env: &mut Env, node: &Node, what: &str) -> Result<f64> {
+    match evaluate_node(env, node)? {
+        Value::Number(n) => Ok(number::Number::from(n).as_f64()),
+        _ => mismatch!("{} must be a number", what),
+    }
+}
+
+fn string_arg(env: &mut Env, node: &Node, what: &str) -> Result<String> {
+    match evaluate_node(env, node)? {
+        Value::String(s) => Ok(s),
+        _ => mismatch!("{} must be a string", what),
+    }
+}
+
+fn int_arg(env: &mut Env, node: &Node, what: &str) -> Result<i64> {
+    match evaluate_node(env, node)? {
+        Value::Number(n) if n.is_i64() => Ok(n.as_i64().expect("is i64")),
+        _ => mismatch!("{} must be an integer", what),
+    }
+}
+
+fn probability_arg(env: &mut Env, node: &Node, what: &str) -> Result<f64> {
+    let p = float_arg(env, node, what)?;
+    ensure_valid!(
+        (0.0..=1.0).contains(&p),
+        "{} must be between 0 and 1, found {}",
+        what,
+        p
+    );
+    Ok(p)
+}
+
+// The random ops, each following its planout-py counterpart
+fn evaluate_random(env: &mut Env, op: &Op, param: Option<&str>) -> Result<Value> {
+    let unit = |env: &mut Env, args: &RandomArgs| -> Result<random::Unit> {
+        let unit = evaluate_node(env, &args.unit)?;
+
+        let salt = match (&args.full_salt, &args.salt) {
+            (Some(full_salt), _) => string_arg(env, full_salt, "full_salt")?,
+            (None, Some(salt)) => {
+                let salt = string_arg(env, salt, "salt")?;
+                format!("{}.{}", env.experiment_salt, salt)
+            }
+            (None, None) => {
+                let param = param.ok_or_else(|| {
+                    invalid("random operators need a salt unless assigned directly to a parameter")
+                })?;
+                format!("{}.{}", env.experiment_salt, param)
+            }
+        };
+
+        Ok(random::Unit::new(&salt, unit))
+    };
+
+    let res = match op {
+        Op::UniformChoice { choices, random } => {
+            let choices = array_arg(env, choices, "uniformChoice choices")?;
+            let unit = unit(env, random)?;
+
+            if choices.is_empty() {
+                return Ok(Value::Array(choices));
+            }
+
+            let i = unit.hash(None) % choices.len() as u64;
+            choices[i as usize].clone()
+        }
+        Op::BernoulliTrial { p, random } => {
+            let p = probability_arg(env, p, "bernoulliTrial p")?;
+            let unit = unit(env, random)?;
+
+            if unit.uniform(0.0, 1.0, None) <= p {
+                1.into()
+            } else {
+                0.into()
+            }
+        }
+        Op::WeightedChoice {
+            choices,
+            weights,
+            random,
+        } => {
+            let choices = array_arg(env, choices, "weightedChoice choices")?;
+            let weights = array_arg(env, weights, "weightedChoice weights")?;
+            let unit = unit(env, random)?;
+
+            ensure_valid!(
+                choices.len() == weights.len(),
+                "weightedChoice needs one weight per choice, found {} choices and {} weights",
+                choices.len(),
+                weights.len()
+            );
+
+            if choices.is_empty() {
+                return Ok(Value::Array(choices));
+            }
+
+            let mut cumulative = Vec::with_capacity(weights.len());
+            let mut sum = 0.0;
+            for weight in weights {
+                match weight {
+                    Value::Number(w) => sum += number::Number::from(w).as_f64(),
+                    _ => mismatch!("weightedChoice weights must be numbers"),
+                }
+                cumulative.push(sum);
+            }
+
+            let stop = unit.uniform(0.0, sum, None);
+            cumulative
+                .iter()
+                .position(|c| stop <= *c)
+                .map_or(Value::Null, |i| choices[i].clone())
+        }
+        Op::RandomInteger { min, max, random } => {
+            let min = int_arg(env, min, "randomInteger min")?;
+            let max = int_arg(env, max, "randomInteger max")?;
+            let unit = unit(env, random)?;
+
+            ensure_valid!(
+                min <= max,
+                "randomInteger min {} is greater than max {}",
+                min,
+                max
+            );
+
+            // The range can be wider than i64 even though the result isn't
+            let range = i128::from(max) - i128::from(min) + 1;
+            let offset = i128::from(unit.hash(None)) % range;
+            i64::try_from(i128::from(min) + offset)
+                .expect("between min and max")
+                .into()
+        }
+        Op::RandomFloat { min, max, random } => {
+            let min = float_arg(env, min, "randomFloat min")?;
+            let max = float_arg(env, max, "randomFloat max")?;
+            let unit = unit(env, random)?;
+
+            finite(
+                number::Number::F64(unit.uniform(min, max, None)),
+                "randomFloat",
+            )?
+        }
+        Op::Sample {
+            choices,
+            draws,
+            random,
+        } => {
+            let mut choices = array_arg(env, choices, "sample choices")?;
+            let draws = match draws {
+                Some(draws) => {
+                    let draws = int_arg(env, draws, "sample draws")?;
+                    ensure_valid!(
+                        draws >= 0,
+                        "sample draws must be at least 0, found {}",
+                        draws
+                    );
+                    // planout-py's assertion
+                    ensure_valid!(
+                        draws as usize <= choices.len(),
+                        "cannot make {} draws when only {} choices are available",
+                        draws,
+                        choices.len()
+                    );
+                    draws as usize
+                }
+                None => choices.len(),
+            };
+            let unit = unit(env, random)?;
+
+            // Fisher-Yates, the swap index hashes the unit plus the position
+            for i in (1..choices.len()).rev() {
+                let j = unit.hash(Some(&i.into())) % (i as u64 + 1);
+                choices.swap(i, j as usize);
+            }
+
+            choices.truncate(draws);
+            choices.into()
+        }
+        Op::BernoulliFilter { p, choices, random } => {
+            let p = probability_arg(env, p, "bernoulliFilter p")?;
+            let choices = array_arg(env, choices, "bernoulliFilter choices")?;
+            let unit = unit(env, random)?;
+
+            // Each choice is kept or not by hashing it with the unit
+            choices
+                .into_iter()
+                .filter(|choice| unit.uniform(0.0, 1.0, Some(choice)) <= p)
+                .collect::<Vec<_>>()
+                .into()
+        }
+        _ => unreachable!("{:?} isn't random", op),
+    };
+
+    Ok(res)
+}
+
+// Nothing orders against null, so the comparison is null too
+fn compare(env: &mut Env, left: &Node, right: &Node, holds: fn(Ordering) -> bool) -> Result<Value> {
+    let left = evaluate_node(env, left)?;
+    let right = evaluate_node(env, right)?;
+
+    let ordering = match (&left, &right) {
+        (Value::Null, _) | (_, Value::Null) => return Ok(Value::Null),
+        (Value::Number(l), Value::Number(r)) => {
+            number::Number::from(l.clone()).partial_cmp(&number::Number::from(r.clone()))
+        }
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        _ => None,
+    };
+
+    ordering
+        .map(|ordering| holds(ordering).into())
+        .ok_or_else(|| EvalError::TypeMismatch {
+            message: format!("cannot compare {} with {}", left, right),
+        })
+}
+
+pub(crate) fn evaluate_op(env: &mut Env, op: &Op) -> Result<serde_json::Value> {
     let res = match op {
         Op::Seq { seq } => {
             for op in seq {
-                evaluate_op(vars, op)?;
+                evaluate_op(env, op)?;
+                if env.returned.is_some() {
+                    break;
+                }
             }
 
-            serde_json::to_value(vars).expect("Vars serializable")
+            Value::Object(env.vars.clone())
         }
         Op::Array { values } => {
             let vs: Vec<serde_json::Value> = values
                 .iter()
-                .map(|v| evaluate_node(vars, v))
-                .collect::<anyhow::Result<_>>()?;
+                .map(|v| evaluate_node(env, v))
+                .collect::<Result<_>>()?;
 
             vs.into()
         }
+        Op::Map { values } => values
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), evaluate_node(env, v)?)))
+            .collect::<Result<serde_json::Map<_, _>>>()?
+            .into(),
         Op::Set { var, value } => {
-            let eval = evaluate_node(vars, value.as_ref())?;
-            vars.insert(var.clone(), eval);
-            serde_json::to_value(vars).unwrap()
+            // A random op assigned to a parameter is salted with its name
+            let eval = match value.as_ref() {
+                Node::Op(op) if op.is_random() => evaluate_random(env, op, Some(var))?,
+                value => evaluate_node(env, value)?,
+            };
+            // Like planout-py, a plan can set its own experiment salt
+            if var == "experiment_salt" {
+                match &eval {
+                    Value::String(salt) => env.experiment_salt = salt.clone(),
+                    _ => mismatch!("experiment_salt must be a string"),
+                }
+            }
+            env.vars.insert(var.clone(), eval);
+            env.assigned.insert(var.clone());
+            Value::Object(env.vars.clone())
         }
 
-        Op::Get(Get { var }) => vars
-            .get(var.as_str())
-            .cloned()
-            .expect(&format!("Environmental variable {} should exist", var))
-            .clone(),
+        Op::Return { value } => {
+            let value = evaluate_node(env, value)?;
+            env.returned = Some(is_truthy(&value));
+            Value::Null
+        }
+        // Like planout-py, variables that were never set are null
+        Op::Get(Get { var }) => env.vars.get(var.as_str()).cloned().unwrap_or(Value::Null),
 
+        // Arithmetic on null is null, so a missing optional input
+        // degrades to null instead of failing the whole plan.
         // TODO planout-py supports stuff like "3" * 5 = "33333"
         // and "3" * true = "3", etc.
         Op::Product { values } => {
-            let p = values.into_iter().fold(anyhow::Result::Ok(1.0), |acc, op| {
-                let acc = acc?;
-                let value = evaluate_node(vars, op)?;
-                match value {
-                    serde_json::Value::Number(n) => Ok(n.as_f64().unwrap() * acc),
-                    _ => anyhow::bail!("multiplication is only defined for numbers"),
+            let mut p = number::Number::I64(1);
+            for value in values {
+                match evaluate_node(env, value)? {
+                    Value::Number(n) => p = p * n.into(),
+                    Value::Null => return Ok(Value::Null),
+                    _ => mismatch!("multiplication is only defined for numbers"),
                 }
-            });
+            }
 
-            p?.into()
+            finite(p, "multiplication")?
         }
         Op::Sum { values } => {
-            let p = values.into_iter().fold(anyhow::Result::Ok(0.0), |acc, op| {
-                let acc = acc?;
-                let value = evaluate_node(vars, op)?;
-                match value {
-                    serde_json::Value::Number(n) => Ok(n.as_f64().unwrap() + acc),
-                    _ => anyhow::bail!("addition is only defined for numbers"),
+            let mut p = number::Number::I64(0);
+            for value in values {
+                match evaluate_node(env, value)? {
+                    Value::Number(n) => p = p + n.into(),
+                    Value::Null => return Ok(Value::Null),
+                    _ => mismatch!("addition is only defined for numbers"),
                 }
-            });
+            }
 
-            p?.into()
+            finite(p, "addition")?
+        }
+        Op::Negative { value } => match evaluate_node(env, value)? {
+            Value::Number(n) => (-number::Number::from(n)).into(),
+            Value::Null => Value::Null,
+            _ => mismatch!("negation is only defined for numbers"),
+        },
+        Op::Divide { left, right } => match numbers(env, left, right, "division")? {
+            Some((l, r)) => finite(
+                l.checked_div(r)
+                    .ok_or_else(|| invalid("division by zero"))?,
+                "division",
+            )?,
+            None => Value::Null,
+        },
+        Op::Mod { left, right } => match numbers(env, left, right, "modulo")? {
+            Some((l, r)) => l
+                .checked_rem(r)
+                .ok_or_else(|| invalid("modulo by zero"))?
+                .into(),
+            None => Value::Null,
+        },
+        // The first value that isn't null, values after it aren't evaluated
+        Op::Coalesce { values } => {
+            for value in values {
+                match evaluate_node(env, value)? {
+                    Value::Null => continue,
+                    value => return Ok(value),
+                }
+            }
+
+            Value::Null
         }
         //Op::Array { values } => values.clone(),
         Op::Cond { cond } => {
             for conditional in cond {
-                if evaluate_node(vars, &conditional.when)?.eq(&serde_json::Value::Bool(true)) {
-                    return evaluate_op(vars, &conditional.then);
+                if is_truthy(&evaluate_node(env, &conditional.when)?) {
+                    return evaluate_op(env, &conditional.then);
                 }
             }
 
             serde_json::Value::Null
         }
-        _ => todo!(),
+        // Like planout-py, missing elements and keys are null
+        Op::Index { base, index } => {
+            let base = evaluate_node(env, base)?;
+            let index = evaluate_node(env, index)?;
+
+            match (&base, &index) {
+                (Value::Array(values), Value::Number(i)) if i.is_i64() || i.is_u64() => i
+                    .as_u64()
+                    .and_then(|i| values.get(usize::try_from(i).ok()?))
+                    .cloned()
+                    .unwrap_or(Value::Null),
+                (Value::Object(map), Value::String(key)) => {
+                    map.get(key).cloned().unwrap_or(Value::Null)
+                }
+                (Value::Object(..), _) | (Value::Null, _) => Value::Null,
+                _ => mismatch!("cannot index {} with {}", base, index),
+            }
+        }
+        Op::Not { value } => (!is_truthy(&evaluate_node(env, value)?)).into(),
+        // Both short-circuit like planout-py, so the remaining
+        // clauses (and any random ops in them) aren't evaluated
+        Op::And { values } => {
+            for value in values {
+                if !is_truthy(&evaluate_node(env, value)?) {
+                    return Ok(false.into());
+                }
+            }
+
+            true.into()
+        }
+        Op::Or { values } => {
+            for value in values {
+                if is_truthy(&evaluate_node(env, value)?) {
+                    return Ok(true.into());
+                }
+            }
+
+            false.into()
+        }
+        Op::Equals { left, right } => {
+            let left = evaluate_node(env, left)?;
+            let right = evaluate_node(env, right)?;
+            values_eq(&left, &right).into()
+        }
+        Op::GreaterThan { left, right } => compare(env, left, right, |o| o == Ordering::Greater)?,
+        Op::LessThan { left, right } => compare(env, left, right, |o| o == Ordering::Less)?,
+        Op::GreaterThanOrEqualTo { left, right } => {
+            compare(env, left, right, |o| o != Ordering::Less)?
+        }
+        Op::LessThanOrEqualTo { left, right } => {
+            compare(env, left, right, |o| o != Ordering::Greater)?
+        }
+        op if op.is_random() => evaluate_random(env, op, None)?,
+        Op::Unknown { op } => return Err(EvalError::UnknownOperator { op: op.clone() }),
+        op => {
+            let op = serde_json::to_value(op).map_or(Value::Null, |op| op["op"].clone());
+            return Err(EvalError::UnknownOperator { op: op.to_string() });
+        }
     };
 
     Ok(res)
 }
 
-pub(crate) fn evaluate_node<'p>(
-    vars: &'p mut Variables,
-    op: &'p Node,
-) -> anyhow::Result<serde_json::Value> {
+pub(crate) fn evaluate_node(env: &mut Env, op: &Node) -> Result<serde_json::Value> {
     match op {
         Node::Json(value) => Ok(value.clone()),
-        Node::Op(op) => evaluate_op(vars, op),
+        Node::Array(values) => values
+            .iter()
+            .map(|v| evaluate_node(env, v))
+            .collect::<Result<Vec<_>>>()
+            .map(Value::Array),
+        Node::Op(op) => evaluate_op(env, op),
     }
 }
 
-pub(crate) fn evaluate(
+/// Runs the plan with `inputs` as its variables, then applies `overrides`
+/// to the parameters it assigned
+pub fn evaluate(
     inputs: &mut Variables,
     overrides: Option<&Variables>,
     plan: &Plan,
-) -> anyhow::Result<serde_json::Value> {
+) -> Result<Evaluation> {
+    // The header is a contract, so it's checked before anything runs
+    for input in plan.inputs() {
+        match inputs.get(&input.name) {
+            None | Some(serde_json::Value::Null) if input.optional => (),
+            None | Some(serde_json::Value::Null) => {
+                return Err(EvalError::UnknownVariable {
+                    name: input.name.clone(),
+                })
+            }
+            Some(value) if !input.ty.accepts(value) => {
+                mismatch!(
+                    "input {} should be a {}, found {}",
+                    input.name,
+                    input.ty,
+                    value
+                )
+            }
+            Some(..) => (),
+        }
+    }
+
+    let mut env = Env {
+        vars: inputs,
+        experiment_salt: plan.salt.clone(),
+        returned: None,
+        assigned: HashSet::new(),
+    };
+
     for op in plan.ops.iter() {
-        evaluate_op(inputs, op)?;
+        evaluate_op(&mut env, op)?;
+        if env.returned.is_some() {
+            break;
+        }
     }
+    let in_experiment = env.returned.unwrap_or(true);
+    let assigned = env.assigned;
 
+    // Params behind a return or an untaken branch were never set
     let mut map: serde_json::Map<String, serde_json::Value> = plan
         .params
         .iter()
-        .map(|param| {
-            (
-                param.to_owned(),
-                inputs
-                    .remove(param)
-                    .expect(format!("param {} is set", param).as_str()),
-            )
-        })
+        .filter(|param| assigned.contains(&param.name))
+        .filter_map(|param| Some((param.name.clone(), inputs.remove(&param.name)?)))
         .collect();
 
-    match overrides {
-        Some(overrides) => {
-            for (key, value) in overrides {
-                let e = map.get_mut(key).ok_or(anyhow::anyhow!(
-                    "expected to override {} but it wasn't a parameter",
-                    key
-                ))?;
-                *e = value.clone();
+    if let Some(overrides) = overrides {
+        for (key, value) in overrides {
+            if !plan.params.iter().any(|param| &param.name == key) {
+                return Err(EvalError::OverrideNotAParam { name: key.clone() });
             }
+            map.insert(key.clone(), value.clone());
         }
+    }
 
-        _ => (),
-    };
-
-    Ok(map.into())
+    Ok(Evaluation {
+        params: map.into(),
+        in_experiment,
+    })
 }
 
 #[cfg(test)]
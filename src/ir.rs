@@ -1,6 +1,7 @@
 /// "Intermediate" Representation. This should be functionally
 /// equivalent to the PlanOut IR references.
-use serde::{Deserialize, Serialize};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 
 pub type Value = serde_json::Value;
 pub type Number = serde_json::Number;
@@ -10,27 +11,175 @@ pub(crate) fn bool_true() -> Node {
     Node::Json(Value::Bool(true))
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Serialize, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum Node {
     Op(Op),
+    // Like planout-py, a plain array's elements are evaluated too
+    Array(Vec<Node>),
     Json(Value),
 }
 
+// Untagged would make an object with an op this doesn't know, or a
+// known op with bad arguments, quietly into a JSON literal
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Node, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+
+        match value {
+            Value::Object(ref object) if object.contains_key("op") => match object["op"].as_str() {
+                Some(op) if !Op::NAMES.contains(&op) => {
+                    Ok(Node::Op(Op::Unknown { op: op.to_string() }))
+                }
+                _ => Op::deserialize(value)
+                    .map(Node::Op)
+                    .map_err(D::Error::custom),
+            },
+            Value::Array(values) => values
+                .into_iter()
+                .map(Node::deserialize)
+                .collect::<Result<_, _>>()
+                .map(Node::Array)
+                .map_err(D::Error::custom),
+            value => Ok(Node::Json(value)),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(tag = "op")]
 #[serde(rename_all = "camelCase")]
 pub enum Op {
-    Set { var: String, value: Box<Node> },
+    Set {
+        var: String,
+        value: Box<Node>,
+    },
     Get(Get),
-    Seq { seq: Vec<Op> },
-    UniformChoice { choices: Box<Op>, unit: Box<Op> },
-    BernoulliTrial { p: f64, unit: Box<Op> },
-    Product { values: Vec<Node> },
-    Sum { values: Vec<Node> },
-    Array { values: Vec<Node> },
-    Cond { cond: Vec<Conditional> },
-    Index { index: String, base: Get },
+    Seq {
+        seq: Vec<Op>,
+    },
+    // Stops the plan, the value's truthiness is whether
+    // the unit is in the experiment
+    Return {
+        value: Box<Node>,
+    },
+    UniformChoice {
+        choices: Box<Node>,
+        #[serde(flatten)]
+        random: RandomArgs,
+    },
+    BernoulliTrial {
+        p: Box<Node>,
+        #[serde(flatten)]
+        random: RandomArgs,
+    },
+    WeightedChoice {
+        choices: Box<Node>,
+        weights: Box<Node>,
+        #[serde(flatten)]
+        random: RandomArgs,
+    },
+    RandomInteger {
+        min: Box<Node>,
+        max: Box<Node>,
+        #[serde(flatten)]
+        random: RandomArgs,
+    },
+    RandomFloat {
+        min: Box<Node>,
+        max: Box<Node>,
+        #[serde(flatten)]
+        random: RandomArgs,
+    },
+    Sample {
+        choices: Box<Node>,
+        // All of the choices when missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        draws: Option<Box<Node>>,
+        #[serde(flatten)]
+        random: RandomArgs,
+    },
+    BernoulliFilter {
+        p: Box<Node>,
+        choices: Box<Node>,
+        #[serde(flatten)]
+        random: RandomArgs,
+    },
+    Product {
+        values: Vec<Node>,
+    },
+    Sum {
+        values: Vec<Node>,
+    },
+    Array {
+        values: Vec<Node>,
+    },
+    // Keys sit next to `op`, e.g. {"op": "map", "color": "red"}
+    Map {
+        #[serde(flatten)]
+        values: BTreeMap<String, Node>,
+    },
+    Coalesce {
+        values: Vec<Node>,
+    },
+    Cond {
+        cond: Vec<Conditional>,
+    },
+    Index {
+        base: Box<Node>,
+        index: Box<Node>,
+    },
+    Not {
+        value: Box<Node>,
+    },
+    Negative {
+        value: Box<Node>,
+    },
+    #[serde(rename = "/")]
+    Divide {
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+    #[serde(rename = "%")]
+    Mod {
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+    And {
+        values: Vec<Node>,
+    },
+    Or {
+        values: Vec<Node>,
+    },
+    Equals {
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+    #[serde(rename = ">")]
+    GreaterThan {
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+    #[serde(rename = "<")]
+    LessThan {
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+    #[serde(rename = ">=")]
+    GreaterThanOrEqualTo {
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+    #[serde(rename = "<=")]
+    LessThanOrEqualTo {
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+    // An op from IR that this doesn't implement, evaluating it fails
+    #[serde(skip)]
+    Unknown {
+        op: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -38,6 +187,18 @@ pub struct Get {
     pub(crate) var: String,
 }
 
+// What every random op hashes. The salt defaults to the name of the
+// parameter the op is assigned to, and `full_salt` replaces both it
+// and the experiment salt.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RandomArgs {
+    pub unit: Box<Node>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<Box<Node>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_salt: Option<Box<Node>>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Conditional {
     #[serde(rename = "if")]
@@ -45,6 +206,97 @@ pub struct Conditional {
     pub then: Op,
 }
 
+impl Op {
+    // Every op's name in the IR
+    pub(crate) const NAMES: &'static [&'static str] = &[
+        "set",
+        "get",
+        "seq",
+        "return",
+        "uniformChoice",
+        "bernoulliTrial",
+        "weightedChoice",
+        "randomInteger",
+        "randomFloat",
+        "sample",
+        "bernoulliFilter",
+        "product",
+        "sum",
+        "array",
+        "map",
+        "coalesce",
+        "cond",
+        "index",
+        "not",
+        "negative",
+        "/",
+        "%",
+        "and",
+        "or",
+        "equals",
+        ">",
+        "<",
+        ">=",
+        "<=",
+    ];
+
+    // Ops that hash their unit, see `random`
+    pub(crate) fn random_args(&self) -> Option<&RandomArgs> {
+        match self {
+            Op::UniformChoice { random, .. }
+            | Op::BernoulliTrial { random, .. }
+            | Op::WeightedChoice { random, .. }
+            | Op::RandomInteger { random, .. }
+            | Op::RandomFloat { random, .. }
+            | Op::Sample { random, .. }
+            | Op::BernoulliFilter { random, .. } => Some(random),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_random(&self) -> bool {
+        self.random_args().is_some()
+    }
+
+    // Variables assigned by this op or any op nested in it, in order, and
+    // whether they might not be, being in a branch or after a return.
+    // Returns whether the op might return.
+    pub(crate) fn assigned_vars(&self, conditional: bool, vars: &mut Vec<(String, bool)>) -> bool {
+        match self {
+            Op::Set { var, .. } => {
+                match vars.iter_mut().find(|(v, _)| v == var) {
+                    Some((_, maybe_unset)) => *maybe_unset &= conditional,
+                    None => vars.push((var.clone(), conditional)),
+                }
+                false
+            }
+            Op::Seq { seq } => assigned_in_seq(seq, conditional, vars),
+            Op::Cond { cond } => {
+                let mut returns = false;
+                for c in cond {
+                    returns |= c.then.assigned_vars(true, vars);
+                }
+                returns
+            }
+            Op::Return { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+// Everything after an op that might return is conditional
+pub(crate) fn assigned_in_seq(
+    seq: &[Op],
+    conditional: bool,
+    vars: &mut Vec<(String, bool)>,
+) -> bool {
+    let mut returns = false;
+    for op in seq {
+        returns |= op.assigned_vars(conditional || returns, vars);
+    }
+    returns
+}
+
 impl TryFrom<Node> for Op {
     type Error = anyhow::Error;
     fn try_from(node: Node) -> anyhow::Result<Op, Self::Error> {
@@ -1,30 +1,189 @@
 #![allow(dead_code)]
+// Errors carry their diagnostic inline so they're easy to match on
+#![allow(clippy::result_large_err)]
 extern crate serde;
 
 #[macro_use]
 extern crate pest_derive;
 
 pub(crate) mod compile;
+pub(crate) mod diagnostic;
+pub(crate) mod error;
 pub(crate) mod eval;
 pub(crate) mod ir;
 pub(crate) mod number;
 pub(crate) mod opt;
 pub(crate) mod or;
+pub(crate) mod random;
 
 type Variable = serde_json::Value;
 type Variables = serde_json::Map<String, Variable>;
 
-pub use compile::compile;
+pub use compile::{compile, compile_all, compile_file, Compilation};
+pub use diagnostic::{Diagnostic, Severity};
+pub use error::{CompileError, EvalError};
+pub use eval::{evaluate, Evaluation};
 
+#[derive(Debug)]
 pub struct Plan {
     ops: Vec<ir::Op>,
-    params: Vec<String>,
+    // In order of first assignment
+    params: Vec<Param>,
+    // Experiment salt, prefixed to every random op's hash
+    salt: String,
+    comments: Vec<Comment>,
+    // The header's declarations, if it has one
+    inputs: Vec<Input>,
+}
+
+/// A parameter the plan outputs, see `Plan::params`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Param {
+    pub name: String,
+    /// Where it's first assigned, 1-based. Both are 0 for plans built
+    /// from IR rather than source.
+    pub line: usize,
+    pub column: usize,
+    /// The byte range of that assignment in the source, empty for plans
+    /// built from IR
+    pub span: std::ops::Range<usize>,
+    /// Only assigned inside `if` or `switch` branches or after a
+    /// `return` that might run, so evaluating the plan might not set it.
+    /// Assignments that can never run don't make a param at all.
+    pub conditional: bool,
+}
+
+/// An input declared in the plan's header, e.g. `input age: number?;`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Input {
+    pub name: String,
+    pub ty: InputType,
+    /// Declared with a `?`, so it can be missing or null
+    pub optional: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputType {
+    String,
+    Number,
+    Boolean,
+    Array,
+    Map,
+}
+
+impl InputType {
+    pub(crate) fn from_name(name: &str) -> Option<InputType> {
+        match name {
+            "string" => Some(InputType::String),
+            "number" => Some(InputType::Number),
+            "boolean" => Some(InputType::Boolean),
+            "array" => Some(InputType::Array),
+            "map" => Some(InputType::Map),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn accepts(self, value: &Variable) -> bool {
+        matches!(
+            (self, value),
+            (InputType::String, Variable::String(..))
+                | (InputType::Number, Variable::Number(..))
+                | (InputType::Boolean, Variable::Bool(..))
+                | (InputType::Array, Variable::Array(..))
+                | (InputType::Map, Variable::Object(..))
+        )
+    }
+}
+
+impl std::fmt::Display for InputType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            InputType::String => "string",
+            InputType::Number => "number",
+            InputType::Boolean => "boolean",
+            InputType::Array => "array",
+            InputType::Map => "map",
+        })
+    }
+}
+
+/// A `#` or `//` comment in the source, kept so tools like a formatter
+/// can put it back. `line` and `column` are 1-based.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comment {
+    /// The comment as written, including the `#` or `//`
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+// planout-py's Interpreter salts with this unless told otherwise
+pub(crate) const DEFAULT_SALT: &str = "global_salt";
+
+// planout-py's convention, assigned but not an output
+pub(crate) fn is_local(var: &str) -> bool {
+    var.starts_with('_')
+}
+
+impl Plan {
+    // A plan from already compiled IR, e.g. the reference compiler's JSON
+    pub(crate) fn from_ops(ops: Vec<ir::Op>) -> Plan {
+        let mut vars = Vec::new();
+        ir::assigned_in_seq(&ops, false, &mut vars);
+        let params = vars
+            .into_iter()
+            .filter(|(var, _)| !is_local(var))
+            .map(|(name, conditional)| Param {
+                name,
+                line: 0,
+                column: 0,
+                span: 0..0,
+                conditional,
+            })
+            .collect();
+
+        Plan {
+            ops,
+            params,
+            salt: DEFAULT_SALT.to_string(),
+            comments: Vec::new(),
+            inputs: Vec::new(),
+        }
+    }
+
+    /// The parameters the plan outputs, in the order the source first
+    /// assigns them
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    /// The inputs the plan's header declares, empty if it has no header
+    pub fn inputs(&self) -> &[Input] {
+        &self.inputs
+    }
+
+    /// The source's comments, in order
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    /// Sets the experiment salt that every random op hashes with,
+    /// usually the experiment's name. Changing it reshuffles every unit.
+    /// Until it's set it's `global_salt`, planout-py's `Interpreter` default.
+    pub fn salt(self, salt: &str) -> Plan {
+        Plan {
+            salt: salt.to_string(),
+            ..self
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{compile::compile, eval::evaluate};
+    use crate::{compile::compile, eval::evaluate, ir, EvalError, Plan, Variables};
+    use serde::Deserialize;
     use serde_json::{json, Value};
+    use std::{fs, path::Path};
 
     fn run_test(
         plan: &'static str,
@@ -37,11 +196,11 @@ mod tests {
 
             match overrides.into() {
                 Some(Value::Object(ov)) => {
-                    let res = evaluate(&mut input, Some(&ov), &ir).unwrap();
+                    let res = evaluate(&mut input, Some(&ov), &ir).unwrap().params;
                     assert_eq!(res, output);
                 }
                 None => {
-                    let res = evaluate(&mut input, None, &ir).unwrap();
+                    let res = evaluate(&mut input, None, &ir).unwrap().params;
                     assert_eq!(res, output);
                 }
                 _ => panic!("overrides not an object"),
@@ -50,6 +209,56 @@ mod tests {
             panic!("input is not an object")
         }
     }
+    // A plan's expected outputs for each set of inputs, as assigned by
+    // planout-py. See tests/fixtures/planout-py/README.md
+    #[derive(Deserialize)]
+    struct Fixture {
+        description: String,
+        salt: String,
+        plan: ir::Op,
+        cases: Vec<FixtureCase>,
+    }
+
+    #[derive(Deserialize)]
+    struct FixtureCase {
+        inputs: Variables,
+        outputs: Value,
+    }
+
+    #[test]
+    fn test_planout_py_fixtures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/planout-py");
+
+        let mut paths = fs::read_dir(&dir)
+            .expect("fixture dir")
+            .map(|entry| entry.expect("fixture").path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert!(!paths.is_empty(), "no fixtures in {}", dir.display());
+
+        for path in paths {
+            let fixture: Fixture =
+                serde_json::from_str(&fs::read_to_string(&path).expect("readable fixture"))
+                    .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            let plan = Plan::from_ops(vec![fixture.plan]).salt(&fixture.salt);
+
+            for case in fixture.cases {
+                let mut inputs = case.inputs.clone();
+                let res = evaluate(&mut inputs, None, &plan)
+                    .unwrap_or_else(|e| panic!("{} with {:?}: {}", path.display(), case.inputs, e));
+                assert_eq!(
+                    res.params,
+                    case.outputs,
+                    "{} ({}) with {:?}",
+                    path.display(),
+                    fixture.description,
+                    case.inputs
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_ret_in_conditional() {
         run_test(
@@ -180,4 +389,648 @@ mod tests {
             }),
         )
     }
+
+    #[test]
+    fn test_comparison_conditional() {
+        let plan = r#"
+            if (country == "US") {
+                p = 0.2;
+            } else if (country != "UK") {
+                p = 0.4;
+            } else {
+                p = 0.1;
+            }
+        "#;
+
+        run_test(plan, json!({"country": "US"}), None, json!({"p": 0.2}));
+        run_test(plan, json!({"country": "CA"}), None, json!({"p": 0.4}));
+        run_test(plan, json!({"country": "UK"}), None, json!({"p": 0.1}));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        run_test(
+            r#"
+            a = x > 2;
+            b = x < 2;
+            c = x >= 3.0;
+            d = x <= 2.5;
+            e = x == 3.0;
+            f = name < "bob";
+            g = true == 1;
+            h = 0.0 == false;
+            i = [true] == [1];
+            j = true == 2;
+            "#,
+            json!({"x": 3, "name": "alice"}),
+            None,
+            json!({
+                "a": true, "b": false, "c": true, "d": false, "e": true, "f": true,
+                "g": true, "h": true, "i": true, "j": false,
+            }),
+        )
+    }
+
+    #[test]
+    fn test_boolean_logic() {
+        let plan = r#"
+            if ((country == "US") && !is_employee) {
+                show = true;
+            } else {
+                show = false;
+            }
+            either = is_employee || (country == "UK");
+        "#;
+
+        run_test(
+            plan,
+            json!({"country": "US", "is_employee": false}),
+            None,
+            json!({"show": true, "either": false}),
+        );
+        run_test(
+            plan,
+            json!({"country": "US", "is_employee": true}),
+            None,
+            json!({"show": false, "either": true}),
+        );
+    }
+
+    #[test]
+    fn test_boolean_short_circuit() {
+        // The right hand sides fail if they're evaluated
+        run_test(
+            r#"
+            a = false && 1 / 0;
+            b = true || bernoulliTrial(p=2, unit=u);
+            "#,
+            json!({"u": 1}),
+            None,
+            json!({"a": false, "b": true}),
+        );
+
+        let plan = compile("a = true && 1 / 0;").expect("compile ok");
+        assert!(evaluate(&mut Variables::new(), None, &plan).is_err());
+        let plan = compile("b = false || bernoulliTrial(p=2, unit=u);").expect("compile ok");
+        let mut input = json!({"u": 1}).as_object().cloned().unwrap();
+        assert!(evaluate(&mut input, None, &plan).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        run_test(
+            r#"
+            bucket = userid % 10;
+            discount = 1 - p;
+            half = userid / 2;
+            neg = -userid;
+            wrapped = (-7) % 3;
+            "#,
+            json!({"userid": 42, "p": 0.25}),
+            None,
+            json!({"bucket": 2, "discount": 0.75, "half": 21.0, "neg": -42, "wrapped": 2}),
+        )
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        for plan in ["x = y / 0;", "x = y % 0;"] {
+            let ir = compile(plan).expect("compile ok");
+            let mut input = json!({"y": 3}).as_object().unwrap().clone();
+            assert!(evaluate(&mut input, None, &ir).is_err());
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        run_test(
+            r#"
+            mul_add = a * b + c;
+            add_mul = c + a * b;
+            parens = a * (b + c);
+            sub_left = c - b - a;
+            div_left = c / b / a;
+            mod_mul = c % b * a;
+            neg_mod = -7 % 3;
+            cmp_sum = a + b > c;
+            cmp_eq = a < b == true;
+            and_or = false && false || true;
+            or_and = true || false && false;
+            not_and = !false && false;
+            mixed = a * b + c > 10 && c % 2 == 0 || false;
+            "#,
+            json!({"a": 2, "b": 3, "c": 4}),
+            None,
+            json!({
+                "mul_add": 10,
+                "add_mul": 10,
+                "parens": 14,
+                "sub_left": -1,
+                "div_left": (4.0 / 3.0) / 2.0,
+                "mod_mul": 2,
+                "neg_mod": 2,
+                "cmp_sum": true,
+                "cmp_eq": true,
+                "and_or": true,
+                "or_and": true,
+                "not_and": false,
+                "mixed": false,
+            }),
+        )
+    }
+
+    #[test]
+    fn test_unparenthesized_targeting() {
+        let plan = r#"
+            if (country == "US" && !is_employee) {
+                show = true;
+            } else {
+                show = false;
+            }
+        "#;
+
+        run_test(
+            plan,
+            json!({"country": "US", "is_employee": false}),
+            None,
+            json!({"show": true}),
+        );
+        run_test(
+            plan,
+            json!({"country": "US", "is_employee": true}),
+            None,
+            json!({"show": false}),
+        );
+    }
+
+    #[test]
+    fn test_random_ops() {
+        // Random ops from source, each op's cases are in the planout-py fixtures
+        let ir = compile(
+            r#"
+            color = uniformChoice(choices=["red", "green", "blue"], unit=userid);
+            show = bernoulliTrial(p=0.3, unit=userid);
+            "#,
+        )
+        .expect("compile ok")
+        .salt("exp");
+
+        for (userid, color, show) in [(1, "red", 1), (2, "red", 0)] {
+            let mut input = json!({ "userid": userid }).as_object().unwrap().clone();
+            let res = evaluate(&mut input, None, &ir).unwrap().params;
+            assert_eq!(res, json!({"color": color, "show": show}));
+        }
+    }
+
+    #[test]
+    fn test_default_salt() {
+        let ir =
+            compile("color = uniformChoice(choices=[\"red\", \"green\", \"blue\"], unit=userid);")
+                .expect("compile ok");
+
+        // planout-py's Interpreter without a salt
+        for (userid, color) in [(1, "blue"), (2, "blue"), (3, "red"), (4, "green")] {
+            let mut input = json!({ "userid": userid }).as_object().unwrap().clone();
+            let res = evaluate(&mut input, None, &ir).unwrap().params;
+            assert_eq!(res, json!({ "color": color }));
+        }
+    }
+
+    #[test]
+    fn test_nested_random_op_needs_salt() {
+        let ir = compile("x = 2 * randomInteger(min=0, max=10, unit=userid);").expect("compile ok");
+        let mut input = json!({"userid": 1}).as_object().unwrap().clone();
+        assert!(evaluate(&mut input, None, &ir).is_err());
+
+        let ir = compile("x = 2 * randomInteger(min=0, max=10, unit=userid, salt=\"x\");")
+            .expect("compile ok");
+        let mut input = json!({"userid": 1}).as_object().unwrap().clone();
+        assert!(evaluate(&mut input, None, &ir).is_ok());
+    }
+
+    #[test]
+    fn test_index_and_field_access() {
+        run_test(
+            r#"
+            choices = [10, 20, 30];
+            second = choices[1];
+            last = choices[i + 1];
+            missing = choices[3];
+            negative = -choices[0];
+            country = user.country;
+            key = config["key"];
+            absent = config.absent;
+            nested = user.devices[0].os;
+            literal = [1, 2, 3][2];
+            "#,
+            json!({
+                "i": 1,
+                "user": {"country": "US", "devices": [{"os": "ios"}]},
+                "config": {"key": "value"},
+            }),
+            None,
+            json!({
+                "choices": [10, 20, 30],
+                "second": 20,
+                "last": 30,
+                "missing": null,
+                "negative": -10,
+                "country": "US",
+                "key": "value",
+                "absent": null,
+                "nested": "ios",
+                "literal": 3,
+            }),
+        )
+    }
+
+    #[test]
+    fn test_map() {
+        run_test(
+            r#"
+            style = {color: "red", "font-size": 12 + 2, tags: [1, 2]};
+            color = style.color;
+            size = style["font-size"];
+            empty = {};
+            "#,
+            json!({}),
+            None,
+            json!({
+                "style": {"color": "red", "font-size": 14, "tags": [1, 2]},
+                "color": "red",
+                "size": 14,
+                "empty": {},
+            }),
+        )
+    }
+
+    #[test]
+    fn test_null_propagation() {
+        run_test(
+            r#"
+            age = coalesce(age, 30);
+            country = coalesce(user.country, user.region, "US");
+            nothing = coalesce(missing, null);
+            next_year = missing + 1;
+            scaled = -missing * 2;
+            ratio = missing / 0;
+            adult = missing >= 18;
+            missing_is_null = isNull(missing);
+            equal = missing == null;
+            indexed = missing[0];
+            if (adult) {
+                branch = "adult";
+            } else {
+                branch = "unknown";
+            }
+            "#,
+            json!({"user": {"region": "EU"}}),
+            None,
+            json!({
+                "age": 30,
+                "country": "EU",
+                "nothing": null,
+                "next_year": null,
+                "scaled": null,
+                "ratio": null,
+                "adult": null,
+                "missing_is_null": true,
+                "equal": true,
+                "indexed": null,
+                "branch": "unknown",
+            }),
+        )
+    }
+
+    #[test]
+    fn test_switch() {
+        let plan = r#"
+            color = "green";
+            switch {
+                country == "US" => color = "red";
+                country == "CA" || country == "MX" => { color = "blue"; }
+                country == "US" => color = "never";
+            }
+            "#;
+
+        run_test(
+            plan,
+            json!({"country": "US"}),
+            None,
+            json!({"color": "red"}),
+        );
+        run_test(
+            plan,
+            json!({"country": "MX"}),
+            None,
+            json!({"color": "blue"}),
+        );
+        run_test(
+            plan,
+            json!({"country": "FR"}),
+            None,
+            json!({"color": "green"}),
+        );
+    }
+
+    #[test]
+    fn test_nested_targeting() {
+        let plan = r#"
+            color = "gray";
+            if (country == "US") {
+                if (platform == "ios") {
+                    if (version >= 17) {
+                        color = "blue";
+                    } else {
+                        color = "navy";
+                    }
+                } else if (platform == "android") {
+                    { color = "green"; size = 2; }
+                } else {
+                    return false;
+                }
+            } else if (country == "CA") {
+                if (platform == "ios") {
+                    return false;
+                }
+                color = "red";
+            }
+        "#;
+
+        let cases = [
+            (
+                json!({"country": "US", "platform": "ios", "version": 17}),
+                json!({"color": "blue"}),
+            ),
+            (
+                json!({"country": "US", "platform": "ios", "version": 16}),
+                json!({"color": "navy"}),
+            ),
+            (
+                json!({"country": "US", "platform": "android"}),
+                json!({"color": "green", "size": 2}),
+            ),
+            (
+                json!({"country": "US", "platform": "web"}),
+                json!({"color": "gray"}),
+            ),
+            (
+                json!({"country": "CA", "platform": "ios"}),
+                json!({"color": "gray"}),
+            ),
+            (
+                json!({"country": "CA", "platform": "web"}),
+                json!({"color": "red"}),
+            ),
+            (
+                json!({"country": "MX", "platform": "ios"}),
+                json!({"color": "gray"}),
+            ),
+        ];
+        for (input, output) in cases {
+            run_test(plan, input, None, output);
+        }
+    }
+
+    #[test]
+    fn test_inputs() {
+        let plan = compile(
+            "input userid: string;\n\
+             input age: number?;\n\
+             adult = age >= 18;\n\
+             color = uniformChoice(choices=[\"red\", \"blue\"], unit=userid);",
+        )
+        .expect("compile ok");
+        let run = |input: Value| {
+            let mut input = input.as_object().cloned().expect("input object");
+            evaluate(&mut input, None, &plan).map(|res| res.params["adult"].clone())
+        };
+
+        assert_eq!(run(json!({"userid": "u1", "age": 20})), Ok(json!(true)));
+        // Optional inputs can be missing or null
+        assert_eq!(run(json!({"userid": "u1"})), Ok(json!(null)));
+        assert_eq!(run(json!({"userid": "u1", "age": null})), Ok(json!(null)));
+
+        assert_eq!(
+            run(json!({"age": 20})),
+            Err(EvalError::UnknownVariable {
+                name: "userid".to_string()
+            })
+        );
+        assert_eq!(
+            run(json!({"userid": 42})),
+            Err(EvalError::TypeMismatch {
+                message: "input userid should be a string, found 42".to_string()
+            })
+        );
+        assert_eq!(
+            run(json!({"userid": "u1", "age": "20"})),
+            Err(EvalError::TypeMismatch {
+                message: "input age should be a number, found \"20\"".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_locals() {
+        let plan = r#"
+            let base_price = 10;
+            _discount = bernoulliTrial(p=0.5, unit=userid);
+            price = base_price * (1 - _discount / 2);
+        "#;
+
+        run_test(plan, json!({"userid": 1}), None, json!({"price": 10.0}));
+        run_test(plan, json!({"userid": 2}), None, json!({"price": 5.0}));
+
+        // Locals can't be overridden, they aren't params
+        let plan = compile(plan).expect("compile ok");
+        let overrides = json!({"base_price": 20}).as_object().cloned().unwrap();
+        assert_eq!(
+            evaluate(&mut Variables::new(), Some(&overrides), &plan).unwrap_err(),
+            EvalError::OverrideNotAParam {
+                name: "base_price".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_in_experiment() {
+        let in_experiment = |plan: &str, input: Value| {
+            let plan = compile(plan).expect("compile ok");
+            let mut input = input.as_object().cloned().expect("input object");
+            evaluate(&mut input, None, &plan).unwrap()
+        };
+
+        let res = in_experiment("x = 1;", json!({}));
+        assert_eq!(res.params, json!({"x": 1}));
+        assert!(res.in_experiment);
+
+        let plan = r#"
+            x = 1;
+            if (country == "US") {
+                if (opted_out) {
+                    return false;
+                }
+                y = 2;
+            } else {
+                return country;
+            }
+            z = 3;
+        "#;
+
+        let res = in_experiment(plan, json!({"country": "US", "opted_out": true}));
+        assert_eq!(res.params, json!({"x": 1}));
+        assert!(!res.in_experiment);
+
+        let res = in_experiment(plan, json!({"country": "US", "opted_out": false}));
+        assert_eq!(res.params, json!({"x": 1, "y": 2, "z": 3}));
+        assert!(res.in_experiment);
+
+        let res = in_experiment(plan, json!({"country": "CA"}));
+        assert_eq!(res.params, json!({"x": 1}));
+        assert!(res.in_experiment);
+
+        let res = in_experiment(plan, json!({"country": ""}));
+        assert!(!res.in_experiment);
+
+        // The reference compiler's JSON
+        let plan = Plan::from_ops(vec![serde_json::from_value(json!({
+            "op": "seq",
+            "seq": [
+                {"op": "set", "var": "x", "value": 1},
+                {"op": "return", "value": 0},
+                {"op": "set", "var": "y", "value": 2}
+            ]
+        }))
+        .unwrap()]);
+        let res = evaluate(&mut Variables::new(), None, &plan).unwrap();
+        assert_eq!(res.params, json!({"x": 1}));
+        assert!(!res.in_experiment);
+
+        // An input named like a param that wasn't set isn't an output
+        let res = in_experiment("if (a) { y = 2; }", json!({"a": false, "y": 7}));
+        assert_eq!(res.params, json!({}));
+    }
+
+    #[test]
+    fn test_eval_error_kinds() {
+        let err = |plan: &str, overrides: Value| {
+            let plan = compile(plan).expect("compile ok");
+            let mut input = json!({"s": "a", "userid": 1}).as_object().unwrap().clone();
+            evaluate(&mut input, overrides.as_object(), &plan).unwrap_err()
+        };
+
+        assert_eq!(
+            err("x = s * 2;", Value::Null),
+            EvalError::TypeMismatch {
+                message: "multiplication is only defined for numbers".to_string()
+            }
+        );
+        assert!(matches!(
+            err("x = s < 2;", Value::Null),
+            EvalError::TypeMismatch { .. }
+        ));
+        assert!(matches!(
+            err("x = [1][s + 1];", Value::Null),
+            EvalError::TypeMismatch { .. }
+        ));
+        assert_eq!(
+            err("x = 1 / 0;", Value::Null),
+            EvalError::InvalidValue {
+                message: "division by zero".to_string()
+            }
+        );
+        // planout-py asserts there are enough choices
+        assert_eq!(
+            err(
+                "x = sample(choices=[1, 2, 3], draws=5, unit=userid);",
+                Value::Null
+            ),
+            EvalError::InvalidValue {
+                message: "cannot make 5 draws when only 3 choices are available".to_string()
+            }
+        );
+        // Rather than becoming null
+        assert_eq!(
+            err("x = 1e308 * 10;", Value::Null),
+            EvalError::InvalidValue {
+                message: "multiplication overflowed".to_string()
+            }
+        );
+        assert_eq!(
+            err(
+                "x = randomFloat(min=-1e308, max=1e308, unit=userid);",
+                Value::Null
+            ),
+            EvalError::InvalidValue {
+                message: "randomFloat overflowed".to_string()
+            }
+        );
+        assert!(matches!(
+            err("x = 1e308 + 1e308;", Value::Null),
+            EvalError::InvalidValue { .. }
+        ));
+        assert!(matches!(
+            err("x = 1e308 / 0.1;", Value::Null),
+            EvalError::InvalidValue { .. }
+        ));
+        assert!(matches!(
+            err("x = bernoulliTrial(p=2, unit=userid);", Value::Null),
+            EvalError::InvalidValue { .. }
+        ));
+        assert_eq!(
+            err("x = 1;", json!({"y": 2})),
+            EvalError::OverrideNotAParam {
+                name: "y".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_ops_in_ir() {
+        let plan = Plan::from_ops(vec![serde_json::from_value(json!({
+            "op": "seq",
+            "seq": [{"op": "set", "var": "x", "value": {"op": "bogus", "value": 1}}]
+        }))
+        .unwrap()]);
+        assert_eq!(
+            evaluate(&mut Variables::new(), None, &plan).unwrap_err(),
+            EvalError::UnknownOperator {
+                op: "bogus".to_string()
+            }
+        );
+
+        // A known op with bad arguments doesn't become a literal either
+        let bad = json!({"op": "set", "var": "x", "value": {"op": "not"}});
+        assert!(serde_json::from_value::<ir::Op>(bad).is_err());
+
+        // Every op's name is known
+        for name in ir::Op::NAMES {
+            let err = serde_json::from_value::<ir::Op>(json!({ "op": name }))
+                .err()
+                .map(|err| err.to_string())
+                .unwrap_or_default();
+            assert!(!err.contains("unknown variant"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_integer_overflow_is_float() {
+        run_test(
+            r#"
+            sum = 9223372036854775807 + 1;
+            big = 18446744073709551615 + 1;
+            product = 18446744073709551615 * 2;
+            min = -(-9223372036854775808);
+            "#,
+            json!({}),
+            None,
+            json!({
+                "sum": 9223372036854775808u64,
+                "big": 18446744073709551616.0,
+                "product": 36893488147419103230.0,
+                "min": 9223372036854775808u64,
+            }),
+        )
+    }
 }
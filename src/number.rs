@@ -1,26 +1,119 @@
-use std::ops::{Add, Mul};
+use std::cmp::Ordering;
+use std::ops::{Add, Mul, Neg};
 
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+#[derive(Copy, Clone, Debug)]
 pub(crate) enum Number {
     I64(i64),
     U64(u64),
     F64(f64),
 }
 
+impl Number {
+    pub(crate) fn as_f64(self) -> f64 {
+        match self {
+            Number::I64(n) => n as f64,
+            Number::U64(n) => n as f64,
+            Number::F64(n) => n,
+        }
+    }
+
+    fn as_i128(self) -> Option<i128> {
+        match self {
+            Number::I64(n) => Some(n.into()),
+            Number::U64(n) => Some(n.into()),
+            Number::F64(..) => None,
+        }
+    }
+
+    // Integers that fit neither an i64 nor a u64 become floats, like Neg
+    fn from_i128(n: i128) -> Self {
+        i64::try_from(n)
+            .map(Number::I64)
+            .or_else(|_| u64::try_from(n).map(Number::U64))
+            .unwrap_or(Number::F64(n as f64))
+    }
+
+    pub(crate) fn is_zero(self) -> bool {
+        self.as_f64() == 0.0
+    }
+
+    // Like planout-py, division is always float division.
+    // None when dividing by zero.
+    pub(crate) fn checked_div(self, rhs: Self) -> Option<Self> {
+        if rhs.is_zero() {
+            return None;
+        }
+
+        Some(Number::F64(self.as_f64() / rhs.as_f64()))
+    }
+
+    // Python's modulo, the result takes the sign of the divisor.
+    // None when dividing by zero.
+    pub(crate) fn checked_rem(self, rhs: Self) -> Option<Self> {
+        if rhs.is_zero() {
+            return None;
+        }
+
+        match (self.as_i128(), rhs.as_i128()) {
+            (Some(l), Some(r)) => {
+                let m = l % r;
+                let m = if m != 0 && (m < 0) != (r < 0) {
+                    m + r
+                } else {
+                    m
+                };
+                // |m| < |r| so it fits in whichever type r has
+                Some(match i64::try_from(m) {
+                    Ok(m) => Number::I64(m),
+                    Err(..) => Number::U64(m as u64),
+                })
+            }
+            _ => {
+                let (l, r) = (self.as_f64(), rhs.as_f64());
+                let m = l % r;
+                let m = if m != 0.0 && (m < 0.0) != (r < 0.0) {
+                    m + r
+                } else {
+                    m
+                };
+                Some(Number::F64(m))
+            }
+        }
+    }
+}
+
+// Like planout-py, ints and floats compare by value: `1 == 1.0`
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        use Number::*;
+
+        match (*self, *other) {
+            (I64(l), I64(r)) => l.partial_cmp(&r),
+            (U64(l), U64(r)) => l.partial_cmp(&r),
+            (I64(l), U64(r)) => match u64::try_from(l) {
+                Ok(l) => l.partial_cmp(&r),
+                Err(..) => Some(Ordering::Less),
+            },
+            (U64(..), I64(..)) => other.partial_cmp(self).map(Ordering::reverse),
+            (l, r) => l.as_f64().partial_cmp(&r.as_f64()),
+        }
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
 impl Add for Number {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self {
-        use Number::*;
-
-        match (self, rhs) {
-            (I64(l), I64(r)) => I64(l + r),
-            (I64(l), U64(r)) => I64(l + i64::try_from(r).unwrap()),
-            (I64(l), F64(r)) => F64(l as f64 + r),
-            (U64(l), U64(r)) => U64(l + r),
-            (F64(l), F64(r)) => F64(l + r),
-            (F64(l), U64(r)) => F64(l + r as f64),
-            (l, r) => r + l,
+        match (self.as_i128(), rhs.as_i128()) {
+            // Both fit in 64 bits, so the sum can't overflow an i128
+            (Some(l), Some(r)) => Number::from_i128(l + r),
+            _ => Number::F64(self.as_f64() + rhs.as_f64()),
         }
     }
 }
@@ -29,32 +122,44 @@ impl Mul for Number {
     type Output = Self;
 
     fn mul(self, rhs: Self) -> Self {
+        match (self.as_i128(), rhs.as_i128()) {
+            (Some(l), Some(r)) => l
+                .checked_mul(r)
+                .map_or(Number::F64(self.as_f64() * rhs.as_f64()), Number::from_i128),
+            _ => Number::F64(self.as_f64() * rhs.as_f64()),
+        }
+    }
+}
+
+impl Neg for Number {
+    type Output = Self;
+
+    fn neg(self) -> Self {
         use Number::*;
 
-        match (self, rhs) {
-            (I64(l), I64(r)) => I64(l * r),
-            (I64(l), U64(r)) => I64(l * i64::try_from(r).unwrap()),
-            (I64(l), F64(r)) => F64(l as f64 * r),
-            (U64(l), U64(r)) => U64(l * r),
-            (F64(l), F64(r)) => F64(l * r),
-            (F64(l), U64(r)) => F64(l * r as f64),
-            (l, r) => r * l,
+        match self {
+            I64(n) => Number::from_i128(-i128::from(n)),
+            U64(n) => Number::from_i128(-i128::from(n)),
+            F64(n) => F64(-n),
         }
     }
 }
 
 impl From<serde_json::Number> for Number {
     fn from(js: serde_json::Number) -> Self {
-        if js.is_i64() {
-            return Self::I64(js.as_i64().unwrap());
-        }
-        if js.is_u64() {
-            return Self::U64(js.as_u64().unwrap());
-        }
-        if js.is_f64() {
-            return Self::F64(js.as_f64().unwrap());
-        }
+        js.as_i64()
+            .map(Self::I64)
+            .or_else(|| js.as_u64().map(Self::U64))
+            .unwrap_or_else(|| Self::F64(js.as_f64().unwrap_or(f64::NAN)))
+    }
+}
 
-        unreachable!()
+impl From<Number> for serde_json::Value {
+    fn from(n: Number) -> Self {
+        match n {
+            Number::I64(n) => n.into(),
+            Number::U64(n) => n.into(),
+            Number::F64(n) => n.into(),
+        }
     }
 }
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use crate::ir::{self, Node, Op};
 use crate::number::Number;
 use crate::or::{self, *};
-use anyhow::{anyhow, bail, ensure, Result};
+use anyhow::{anyhow, Result};
 
 // lifetimes questionable
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
@@ -12,18 +12,11 @@ enum Var {
     Branch(Vec<Var>),
 }
 
+#[derive(Default)]
 struct State {
     inner: HashMap<String, Var>,
 }
 
-impl Default for State {
-    fn default() -> Self {
-        State {
-            inner: HashMap::default(),
-        }
-    }
-}
-
 impl State {
     fn insert(&mut self, var: &str, val: &Val) {
         self.inner.insert(var.to_string(), Var::Val(val.clone()));
@@ -77,6 +70,51 @@ fn optimize_product(values: Vec<ir::Node>, state: &mut State) -> Result<Mul> {
     optimize_mul(vals)
 }
 
+// Drops constant clauses that can't decide the result (`short`
+// is the value that short-circuits: false for and, true for or)
+// and stops at the first constant that does.
+fn optimize_logic(values: Vec<Node>, short: bool, state: &mut State) -> Result<Option<Vec<Val>>> {
+    let mut rest = Vec::new();
+
+    for value in values {
+        match optimize_node(value, state)? {
+            Val::Bool(b) if b == short => {
+                if rest.is_empty() {
+                    return Ok(None);
+                }
+                rest.push(Val::Bool(b));
+                break;
+            }
+            Val::Bool(..) => continue,
+            val => rest.push(val),
+        }
+    }
+
+    Ok(Some(rest))
+}
+
+// Folds the comparison when both sides are known constants
+fn optimize_compare(op: Comparison, lhs: Node, rhs: Node, state: &mut State) -> Result<Val> {
+    let lhs = optimize_node(lhs, state)?;
+    let rhs = optimize_node(rhs, state)?;
+
+    let ordering = match (&lhs, &rhs) {
+        (Val::Number(l), Val::Number(r)) => l.partial_cmp(r),
+        (Val::String(l), Val::String(r)) => Some(l.cmp(r)),
+        (Val::Bool(l), Val::Bool(r)) => Some(l.cmp(r)),
+        _ => None,
+    };
+
+    Ok(match ordering {
+        Some(ordering) => Val::Bool(op.holds(ordering)),
+        None => Val::Compare(Compare {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        }),
+    })
+}
+
 // supposably could generate 1 or more Val, this could probably
 // take a handle to the stack
 fn optimize_node(node: Node, state: &mut State) -> Result<Val> {
@@ -84,6 +122,7 @@ fn optimize_node(node: Node, state: &mut State) -> Result<Val> {
         Node::Json(ir::Value::Number(num)) => Val::Number(Number::from(num)),
         Node::Json(ir::Value::String(str)) => Val::String(str),
         Node::Json(ir::Value::Bool(b)) => Val::Bool(b),
+        Node::Json(ir::Value::Null) => Val::Null(Null),
 
         Node::Op(ir::Op::Get(ir::Get { var })) => {
             match state.get(var.as_str()) {
@@ -113,6 +152,18 @@ fn optimize_node(node: Node, state: &mut State) -> Result<Val> {
                 .map(|n| optimize_node(n, state))
                 .collect::<Result<Vec<_>>>()?;
 
+            let nums = values
+                .iter()
+                .map(|val| match val {
+                    Val::Number(n) => Some(*n),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+
+            if let Some(n) = nums.and_then(|nums| nums.into_iter().reduce(|acc, n| acc + n)) {
+                return Ok(Val::Number(n));
+            }
+
             let lhs = Box::new(values.pop().unwrap());
             let rhs = Box::new(Val::Stack(Stack { inner: values }));
 
@@ -126,13 +177,47 @@ fn optimize_node(node: Node, state: &mut State) -> Result<Val> {
                 .collect::<Result<Vec<_>>>()?,
         }),
 
-        Node::Op(Op::Array { values }) => Val::Array(Array(
+        Node::Op(Op::Array { values }) | Node::Array(values) => Val::Array(Array(
             values
                 .into_iter()
                 .map(|n| optimize_node(n, state))
                 .collect::<Result<Vec<_>>>()?,
         )),
 
+        Node::Op(Op::Map { values }) => Val::Map(Map(values
+            .into_iter()
+            .map(|(k, v)| Ok((k, optimize_node(v, state)?)))
+            .collect::<Result<_>>()?)),
+
+        Node::Op(Op::Coalesce { values }) => {
+            let mut rest = Vec::with_capacity(values.len());
+            for value in values {
+                match optimize_node(value, state)? {
+                    Val::Null(..) => continue,
+                    // Known not to be null, so nothing after it matters
+                    value @ (Val::Number(..) | Val::Bool(..) | Val::String(..))
+                        if rest.is_empty() =>
+                    {
+                        return Ok(value)
+                    }
+                    value @ (Val::Number(..) | Val::Bool(..) | Val::String(..)) => {
+                        rest.push(value);
+                        break;
+                    }
+                    value => rest.push(value),
+                }
+            }
+
+            match rest.len() {
+                0 => Val::Null(Null),
+                _ => Val::Coalesce(Coalesce { values: rest }),
+            }
+        }
+
+        Node::Op(Op::Return { value }) => Val::Return(Return {
+            value: Box::new(optimize_node(*value, state)?),
+        }),
+
         Node::Op(Op::Cond { cond }) => Val::Cond(Cond {
             inner: cond
                 .into_iter()
@@ -153,6 +238,72 @@ fn optimize_node(node: Node, state: &mut State) -> Result<Val> {
                 .collect::<Result<Vec<_>>>()?,
         }),
 
+        Node::Op(Op::Negative { value }) => match optimize_node(*value, state)? {
+            Val::Number(n) => Val::Number(-n),
+            value => Val::Neg(Neg {
+                value: Box::new(value),
+            }),
+        },
+
+        Node::Op(Op::Divide { left, right }) => {
+            match (optimize_node(*left, state)?, optimize_node(*right, state)?) {
+                (Val::Number(l), Val::Number(r)) => {
+                    Val::Number(l.checked_div(r).ok_or(anyhow!("division by zero"))?)
+                }
+                (lhs, rhs) => Val::Div(Div {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }),
+            }
+        }
+
+        Node::Op(Op::Mod { left, right }) => {
+            match (optimize_node(*left, state)?, optimize_node(*right, state)?) {
+                (Val::Number(l), Val::Number(r)) => {
+                    Val::Number(l.checked_rem(r).ok_or(anyhow!("modulo by zero"))?)
+                }
+                (lhs, rhs) => Val::Mod(Mod {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }),
+            }
+        }
+
+        Node::Op(Op::Not { value }) => match optimize_node(*value, state)? {
+            Val::Bool(b) => Val::Bool(!b),
+            value => Val::Not(Not {
+                value: Box::new(value),
+            }),
+        },
+
+        Node::Op(Op::And { values }) => match optimize_logic(values, false, state)? {
+            None => Val::Bool(false),
+            Some(values) if values.is_empty() => Val::Bool(true),
+            Some(values) => Val::And(And { values }),
+        },
+
+        Node::Op(Op::Or { values }) => match optimize_logic(values, true, state)? {
+            None => Val::Bool(true),
+            Some(values) if values.is_empty() => Val::Bool(false),
+            Some(values) => Val::Or(Or { values }),
+        },
+
+        Node::Op(Op::Equals { left, right }) => {
+            optimize_compare(Comparison::Eq, *left, *right, state)?
+        }
+        Node::Op(Op::GreaterThan { left, right }) => {
+            optimize_compare(Comparison::Gt, *left, *right, state)?
+        }
+        Node::Op(Op::LessThan { left, right }) => {
+            optimize_compare(Comparison::Lt, *left, *right, state)?
+        }
+        Node::Op(Op::GreaterThanOrEqualTo { left, right }) => {
+            optimize_compare(Comparison::Ge, *left, *right, state)?
+        }
+        Node::Op(Op::LessThanOrEqualTo { left, right }) => {
+            optimize_compare(Comparison::Le, *left, *right, state)?
+        }
+
         _ => unimplemented!(),
     })
 }
@@ -161,8 +312,7 @@ fn optimize(nodes: Vec<Op>) -> Result<Stack> {
     let mut state = State::default();
     let mut stack = Vec::with_capacity(nodes.len());
 
-    let mut nodes = nodes.into_iter();
-    while let Some(node) = nodes.next() {
+    for node in nodes {
         stack.push(optimize_node(Node::Op(node), &mut state)?);
     }
 
@@ -256,4 +406,134 @@ mod tests {
             },
         )
     }
+
+    #[test]
+    fn test_compare_opt() {
+        assert_stack(
+            r#"
+            y = 2 >= 1.5;
+            z = x == "US";
+        "#,
+            Stack {
+                inner: vec![
+                    Val::Assign(Assign {
+                        var: "y".to_owned(),
+                        value: Box::new(Val::Bool(true)),
+                    }),
+                    Val::Assign(Assign {
+                        var: "z".to_owned(),
+                        value: Box::new(Val::Compare(Compare {
+                            op: Comparison::Eq,
+                            lhs: Box::new(Val::Param(Param {
+                                name: "x".to_string(),
+                            })),
+                            rhs: Box::new(Val::String("US".to_string())),
+                        })),
+                    }),
+                ],
+            },
+        )
+    }
+
+    #[test]
+    fn test_logic_opt() {
+        let x = Val::Param(Param {
+            name: "x".to_string(),
+        });
+        assert_stack(
+            r#"
+            a = true && x;
+            b = x || true;
+            c = false && x;
+        "#,
+            Stack {
+                inner: vec![
+                    Val::Assign(Assign {
+                        var: "a".to_owned(),
+                        value: Box::new(Val::And(And {
+                            values: vec![x.clone()],
+                        })),
+                    }),
+                    Val::Assign(Assign {
+                        var: "b".to_owned(),
+                        value: Box::new(Val::Or(Or {
+                            values: vec![x, Val::Bool(true)],
+                        })),
+                    }),
+                    Val::Assign(Assign {
+                        var: "c".to_owned(),
+                        value: Box::new(Val::Bool(false)),
+                    }),
+                ],
+            },
+        )
+    }
+
+    #[test]
+    fn test_arithmetic_opt() {
+        assert_stack(
+            r#"
+            a = 1 - 0.25;
+            b = 7 % -3;
+            c = x / 2;
+        "#,
+            Stack {
+                inner: vec![
+                    Val::Assign(Assign {
+                        var: "a".to_owned(),
+                        value: Box::new(Val::Number(Number::F64(0.75))),
+                    }),
+                    Val::Assign(Assign {
+                        var: "b".to_owned(),
+                        value: Box::new(Val::Number(Number::I64(-2))),
+                    }),
+                    Val::Assign(Assign {
+                        var: "c".to_owned(),
+                        value: Box::new(Val::Div(Div {
+                            lhs: Box::new(Val::Param(Param {
+                                name: "x".to_string(),
+                            })),
+                            rhs: Box::new(Val::Number(Number::I64(2))),
+                        })),
+                    }),
+                ],
+            },
+        );
+
+        assert!(optimize(compile("a = 1 / 0;").unwrap().ops).is_err());
+    }
+
+    #[test]
+    fn test_coalesce_opt() {
+        assert_stack(
+            r#"
+            a = coalesce(null, 2, x);
+            b = coalesce(x, null, "default", y);
+            c = coalesce(null, null);
+        "#,
+            Stack {
+                inner: vec![
+                    Val::Assign(Assign {
+                        var: "a".to_owned(),
+                        value: Box::new(Val::Number(Number::I64(2))),
+                    }),
+                    Val::Assign(Assign {
+                        var: "b".to_owned(),
+                        value: Box::new(Val::Coalesce(Coalesce {
+                            values: vec![
+                                Val::Param(Param {
+                                    name: "x".to_string(),
+                                }),
+                                Val::String("default".to_string()),
+                            ],
+                        })),
+                    }),
+                    Val::Assign(Assign {
+                        var: "c".to_owned(),
+                        value: Box::new(Val::Null(Null)),
+                    }),
+                ],
+            },
+        );
+    }
 }
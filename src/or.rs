@@ -1,5 +1,7 @@
 /// "Optimized" Representation
 use crate::number::Number;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
 macro_rules! types {
     ($($ty:ident),+) => {
@@ -32,11 +34,20 @@ macro_rules! types {
     };
 }
 
-types!(Number, Bool, String, Assign, Pointer, Param, Array, Stack, Mul, Sum, Cond);
+types!(
+    Number, Bool, String, Assign, Pointer, Param, Array, Stack, Mul, Sum, Cond, Not, Compare, And,
+    Or, Neg, Div, Mod, Map, Null, Coalesce, Return
+);
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub(crate) struct Array(pub(crate) Vec<Val>);
 
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Map(pub(crate) BTreeMap<String, Val>);
+
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Null;
+
 pub type Bool = bool;
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -74,6 +85,83 @@ pub(crate) struct Sum {
     pub(crate) rhs: Box<Val>,
 }
 
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Neg {
+    pub(crate) value: Box<Val>,
+}
+
+// Always float division
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Div {
+    pub(crate) lhs: Box<Val>,
+    pub(crate) rhs: Box<Val>,
+}
+
+// Python's modulo, takes the sign of the divisor
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Mod {
+    pub(crate) lhs: Box<Val>,
+    pub(crate) rhs: Box<Val>,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Not {
+    pub(crate) value: Box<Val>,
+}
+
+// Short-circuiting, values are evaluated in order
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct And {
+    pub(crate) values: Vec<Val>,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Or {
+    pub(crate) values: Vec<Val>,
+}
+
+// Stops the plan, truthy if the unit is in the experiment
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Return {
+    pub(crate) value: Box<Val>,
+}
+
+// First value that isn't null
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Coalesce {
+    pub(crate) values: Vec<Val>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd)]
+pub(crate) enum Comparison {
+    Eq,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+}
+
+impl Comparison {
+    // Whether `lhs <op> rhs` holds given how lhs orders against rhs
+    pub(crate) fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Eq => ordering == Ordering::Equal,
+            Comparison::Gt => ordering == Ordering::Greater,
+            Comparison::Lt => ordering == Ordering::Less,
+            Comparison::Ge => ordering != Ordering::Less,
+            Comparison::Le => ordering != Ordering::Greater,
+        }
+    }
+}
+
+// ==, >, <, >=, <=
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub(crate) struct Compare {
+    pub(crate) op: Comparison,
+    pub(crate) lhs: Box<Val>,
+    pub(crate) rhs: Box<Val>,
+}
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub(crate) struct Branch {
     // If some, evaluate for truth, else always then
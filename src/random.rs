@@ -0,0 +1,216 @@
+//! Deterministic hashing shared by the random operators. This has to
+//! match planout-py's `PlanOutOpRandom` byte for byte: services running
+//! either implementation must put a unit in the same bucket.
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+
+// The largest hash, i.e. 15 hex digits
+const LONG_SCALE: f64 = 0xFFF_FFFF_FFFF_FFFF_u64 as f64;
+
+// What a random op hashes, its salts and its unit
+pub(crate) struct Unit {
+    // `experiment_salt.salt.`, or `full_salt.`
+    prefix: String,
+    values: Vec<Value>,
+}
+
+impl Unit {
+    // An array is a multi-unit, e.g. `unit=[userid, itemid]`
+    // `salt` is the full salt, normally `experiment_salt.salt`
+    pub(crate) fn new(salt: &str, unit: Value) -> Self {
+        let values = match unit {
+            Value::Array(values) => values,
+            unit => vec![unit],
+        };
+
+        Unit {
+            prefix: format!("{}.", salt),
+            values,
+        }
+    }
+
+    // `getHash`, the first 15 hex digits of the SHA1 of
+    // `experiment_salt.salt.unit` (plus `.appended` when given).
+    // A multi-unit's values are joined with `.` too.
+    //
+    // planout-py ASCII encodes the string and fails on anything else,
+    // we hash the UTF-8 instead which agrees wherever planout-py works.
+    pub(crate) fn hash(&self, appended: Option<&Value>) -> u64 {
+        let unit = self
+            .values
+            .iter()
+            .chain(appended)
+            .map(py_str)
+            .collect::<Vec<_>>()
+            .join(".");
+
+        let digest = Sha1::digest(format!("{}{}", self.prefix, unit).as_bytes());
+
+        let mut head = [0; 8];
+        head.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(head) >> 4
+    }
+
+    // `getUniform`, a float in [min, max]
+    pub(crate) fn uniform(&self, min: f64, max: f64, appended: Option<&Value>) -> f64 {
+        min + (max - min) * (self.hash(appended) as f64 / LONG_SCALE)
+    }
+}
+
+// Python's `str`, which is how planout-py turns units into text
+pub(crate) fn py_str(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => py_repr(value),
+    }
+}
+
+fn py_repr(value: &Value) -> String {
+    match value {
+        Value::Null => "None".to_string(),
+        Value::Bool(true) => "True".to_string(),
+        Value::Bool(false) => "False".to_string(),
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() => py_float(f),
+            _ => n.to_string(),
+        },
+        Value::String(s) => py_repr_str(s),
+        Value::Array(values) => {
+            let values = values.iter().map(py_repr).collect::<Vec<_>>();
+            format!("[{}]", values.join(", "))
+        }
+        Value::Object(map) => {
+            let items = map
+                .iter()
+                .map(|(k, v)| format!("{}: {}", py_repr_str(k), py_repr(v)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", items.join(", "))
+        }
+    }
+}
+
+// Single quoted unless the string has single but no double quotes
+fn py_repr_str(s: &str) -> String {
+    let quote = if s.contains('\'') && !s.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+
+    let mut repr = String::with_capacity(s.len() + 2);
+    repr.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => repr.push_str("\\\\"),
+            '\n' => repr.push_str("\\n"),
+            '\r' => repr.push_str("\\r"),
+            '\t' => repr.push_str("\\t"),
+            c if c == quote => {
+                repr.push('\\');
+                repr.push(c);
+            }
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                repr.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c => repr.push(c),
+        }
+    }
+    repr.push(quote);
+
+    repr
+}
+
+// Python's float repr: the shortest digits that round trip, written
+// positionally when the exponent is in [-4, 16) and as `1e+16` otherwise
+fn py_float(f: f64) -> String {
+    if f.is_nan() {
+        return "nan".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if f == 0.0 {
+        return if f.is_sign_negative() { "-0.0" } else { "0.0" }.to_string();
+    }
+
+    // Rust's `{:e}` has the same shortest round trip digits, e.g. -1.25e-7
+    let sci = format!("{:e}", f);
+    let (mantissa, exp) = sci.split_once('e').expect("float exponent");
+    let exp: i32 = exp.parse().expect("float exponent");
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(mantissa) => ("-", mantissa),
+        None => ("", mantissa),
+    };
+    let digits = mantissa.replace('.', "");
+
+    if (-4..16).contains(&exp) {
+        if exp < 0 {
+            return format!("{}0.{}{}", sign, "0".repeat((-exp - 1) as usize), digits);
+        }
+
+        let point = exp as usize + 1;
+        if digits.len() <= point {
+            format!("{}{}{}.0", sign, digits, "0".repeat(point - digits.len()))
+        } else {
+            format!("{}{}.{}", sign, &digits[..point], &digits[point..])
+        }
+    } else {
+        let mantissa = match digits.len() {
+            1 => digits,
+            _ => format!("{}.{}", &digits[..1], &digits[1..]),
+        };
+        let exp_sign = if exp < 0 { '-' } else { '+' };
+        format!("{}{}e{}{:02}", sign, mantissa, exp_sign, exp.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Expected values come from planout-py's PlanOutOpRandom
+    #[test]
+    fn test_hash_matches_planout_py() {
+        let unit = |unit| Unit::new("exp.color", unit);
+
+        assert_eq!(unit(json!(1)).hash(None), 1003259912053668924);
+        assert_eq!(unit(json!(2)).hash(None), 346529122885472349);
+        assert_eq!(unit(json!("alice")).hash(None), 655647795140978207);
+        assert_eq!(unit(json!(1.0)).hash(None), 1096059898711273546);
+        assert_eq!(unit(json!(1)).uniform(0.0, 1.0, None), 0.8701892609729631);
+    }
+
+    #[test]
+    fn test_multi_unit_hash_matches_planout_py() {
+        let unit = |unit| Unit::new("exp.color", unit);
+
+        assert_eq!(unit(json!([1, "a"])).hash(None), 656068287605650361);
+        assert_eq!(unit(json!([1, 2.0, "x"])).hash(None), 141730370626551161);
+        // Same string as the multi-unit [1, "a"]
+        assert_eq!(unit(json!(1)).hash(Some(&json!("a"))), 656068287605650361);
+    }
+
+    #[test]
+    fn test_py_str() {
+        let cases = [
+            (json!(1e16), "1e+16"),
+            (json!(1.5e-7), "1.5e-07"),
+            (json!(0.0001), "0.0001"),
+            (json!(123456.789), "123456.789"),
+            (json!(-2.5), "-2.5"),
+            (json!(1e22), "1e+22"),
+            (json!(0.1), "0.1"),
+            (json!(3.0), "3.0"),
+            (json!("it's"), "it's"),
+            (
+                json!([1, "a", null, true, 2.0, "it's"]),
+                r#"[1, 'a', None, True, 2.0, "it's"]"#,
+            ),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(py_str(&value), expected);
+        }
+    }
+}